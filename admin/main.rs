@@ -0,0 +1,129 @@
+use pusher::base64::base64url_decode;
+use pusher::db::{check_schema, get_pool};
+use pusher::err::Result;
+use pusher::push::Sender;
+use pusher::subscription::{
+    delete_subscriptions, find_subscriptions, get_subscriptions, list_subscriptions,
+    rename_subscription, SubscriptionInfo, SubscriptionRef,
+};
+use pusher::utils::{get_var, parse_var_or, to_array};
+use std::env;
+
+enum Command {
+    List { json: bool },
+    Rename { sub: SubscriptionRef, name: String },
+    Delete { sub: SubscriptionRef },
+    Export,
+    TestPush { sub: SubscriptionRef },
+}
+
+impl Command {
+    fn from_args() -> Result<Self> {
+        let mut args = env::args();
+        let progname = args.next().ok_or("invalid args")?;
+        let usage = format!(
+            "usage: {progname} list [--json] | rename <id|name> <new-name> | delete <id|name> | export \
+            | test-push <id|name>"
+        );
+        let args: Vec<_> = args.collect();
+        let args: Vec<_> = args.iter().map(String::as_str).collect();
+        match args.as_slice() {
+            ["list"] => Ok(Self::List { json: false }),
+            ["list", "--json"] => Ok(Self::List { json: true }),
+            ["rename", sub, name] => Ok(Self::Rename {
+                sub: sub.parse()?,
+                name: name.to_string(),
+            }),
+            ["delete", sub] => Ok(Self::Delete { sub: sub.parse()? }),
+            ["export"] => Ok(Self::Export),
+            ["test-push", sub] => Ok(Self::TestPush { sub: sub.parse()? }),
+            _ => Err(usage.into()),
+        }
+    }
+}
+
+/// Print the subscriptions as a table with aligned columns
+fn print_table(subs: &[SubscriptionInfo]) {
+    let header = ["id", "name", "endpoint host", "inserted", "expiration"].map(String::from);
+    let rows: Vec<[String; 5]> = subs
+        .iter()
+        .map(|s| {
+            [
+                s.id.to_string(),
+                s.name.clone(),
+                s.endpoint_host.clone(),
+                s.inserted.clone(),
+                s.expiration_time
+                    .map_or(String::from("-"), |e| e.to_string()),
+            ]
+        })
+        .collect();
+    let mut widths = header.clone().map(|h| h.chars().count());
+    for row in &rows {
+        for (width, col) in widths.iter_mut().zip(row) {
+            *width = (*width).max(col.chars().count());
+        }
+    }
+    for row in [header].iter().chain(&rows) {
+        let cols: Vec<_> = row
+            .iter()
+            .zip(widths)
+            .map(|(col, width)| format!("{col:width$}"))
+            .collect();
+        println!("{}", cols.join("  ").trim_end());
+    }
+}
+
+fn encryption_key() -> Result<[u8; 16]> {
+    get_var("DATABASE_ENCRYPTION_KEY")
+        .and_then(base64url_decode)
+        .and_then(to_array)
+}
+
+#[tokio::main]
+async fn run(command: Command) -> Result<()> {
+    let pool = get_pool(&get_var("DATABASE_PATH")?, false)?;
+    check_schema(&pool).await?;
+    match command {
+        Command::List { json: false } => print_table(&list_subscriptions(&pool).await?),
+        Command::List { json: true } => {
+            let subs = list_subscriptions(&pool).await?;
+            println!("{}", serde_json::to_string_pretty(&subs)?);
+        }
+        Command::Rename { sub, name } => {
+            match rename_subscription(&pool, sub.clone(), name).await? {
+                0 => return Err(format!("no subscription with {sub}").into()),
+                n => println!("renamed {n} subscriptions"),
+            }
+        }
+        Command::Delete { sub } => match delete_subscriptions(&pool, sub.clone()).await? {
+            0 => return Err(format!("no subscription with {sub}").into()),
+            n => println!("deleted {n} subscriptions"),
+        },
+        Command::Export => {
+            let subs = get_subscriptions(&pool, encryption_key()?).await?;
+            println!("{}", serde_json::to_string_pretty(&subs)?);
+        }
+        Command::TestPush { sub } => {
+            let sender = Sender::from_env()?;
+            let title = parse_var_or("PUSH_TEST_TITLE", String::from("pusher"))?;
+            let subs = find_subscriptions(&pool, encryption_key()?, sub.clone()).await?;
+            let sub = match subs.as_slice() {
+                [] => return Err(format!("no subscription with {sub}").into()),
+                [sub] => sub,
+                _ => return Err(format!("several subscriptions with {sub}, use the id").into()),
+            };
+            println!("{}", sub.endpoint());
+            println!("{}", sender.send_diagnostic(sub, &title).await?);
+        }
+    }
+    Ok(())
+}
+
+/// Manage the subscriptions in the database.
+fn main() {
+    if let Err(e) = Command::from_args().and_then(run) {
+        eprintln!("{e}");
+        std::process::exit(1)
+    };
+}
@@ -26,9 +26,10 @@ async function subscribeToPush() {
       .then(json => json.vapid_public_key)
   };
   const subscription = await registration.pushManager.subscribe(sub_data);
-  await updateButtons();
   const sub = subscription.toJSON();
   sub.name = sub_name_field.value;
+  sub.topics = selectedTopics();
+  sub.contentEncodings = PushManager.supportedContentEncodings ?? [];
 
   const resp = await fetch('/subscribe', {
     method: 'POST',
@@ -36,9 +37,54 @@ async function subscribeToPush() {
     body: JSON.stringify(sub)
   });
   sub_name_field.value = "";
+  await updateButtons();
   return resp
 }
 
+async function renderTopics(selected) {
+  const topics_field = document.getElementById('topics');
+  const topics = await fetch('/topics')
+    .then(resp => resp.json())
+    .then(json => json.topics);
+  topics_field.replaceChildren(...topics.map(topic => {
+    const label = document.createElement('label');
+    const checkbox = document.createElement('input');
+    checkbox.type = 'checkbox';
+    checkbox.value = topic;
+    checkbox.checked = selected.includes(topic);
+    label.append(checkbox, ` ${topic}`);
+    return label;
+  }));
+  topics_field.hidden = topics.length === 0;
+  return topics.length > 0;
+}
+
+function selectedTopics() {
+  const checked = document.querySelectorAll('#topics input:checked');
+  return Array.from(checked, checkbox => checkbox.value);
+}
+
+async function saveTopics() {
+  const registration = await navigator.serviceWorker.getRegistration();
+  if (registration === undefined) return;
+
+  const subscription = await registration.pushManager.getSubscription();
+  if (subscription === null) return;
+
+  return await fetch('/subscription/topics', {
+    method: 'PUT',
+    headers: { 'Content-Type': 'application/json' },
+    body: JSON.stringify({ endpoint: subscription.endpoint, topics: selectedTopics() })
+  });
+}
+
+async function subscriptionTopics(subscription) {
+  const endpoint_query = new URLSearchParams({ endpoint: subscription.endpoint });
+  return await fetch(`/subscription/topics?${endpoint_query}`)
+    .then(resp => resp.json())
+    .then(json => json.topics);
+}
+
 async function unsubscribeFromPush() {
   const registration = await navigator.serviceWorker.getRegistration();
   if (registration === undefined) return;
@@ -67,6 +113,8 @@ async function updateButtons() {
   const sub_name_field = document.getElementById('subscriptionName');
   const test_button = document.getElementById('testButton');
   const message_field = document.getElementById('testMessage');
+  const topics_field = document.getElementById('topics');
+  const topics_button = document.getElementById('saveTopics');
 
   const registration = await navigator.serviceWorker.getRegistration();
   const registered = registration !== undefined;
@@ -76,6 +124,8 @@ async function updateButtons() {
   sub_button.disabled = !registered;
   sub_name_field.hidden = !registered;
   message_field.hidden = !registered;
+  topics_field.hidden = true;
+  topics_button.hidden = true;
   if (!registered) return;
 
   const subscription = await registration.pushManager.getSubscription();
@@ -83,6 +133,9 @@ async function updateButtons() {
   sub_button.textContent = subscribed ? "unsubscribe" : "subscribe";
   sub_button.onclick = subscribed ? unsubscribeFromPush : subscribeToPush
   sub_name_field.hidden = subscribed;
+  const has_topics = await renderTopics(subscribed ? await subscriptionTopics(subscription) : []);
+  topics_button.hidden = !(subscribed && has_topics);
+  topics_button.onclick = saveTopics;
   test_button.disabled = !subscribed;
   message_field.hidden = !subscribed;
   if (!test_button.disabled) {
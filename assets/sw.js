@@ -5,3 +5,18 @@ self.addEventListener('push', (event) => {
     notification.options
   );
 });
+
+// Open the url of the clicked action or of the notification itself, the app by default
+self.addEventListener('notificationclick', (event) => {
+  event.notification.close();
+  const data = event.notification.data ?? {};
+  const url = data.actions?.[event.action] ?? data.url ?? self.registration.scope;
+  event.waitUntil(focusOrOpen(new URL(url, self.registration.scope).href));
+});
+
+async function focusOrOpen(url) {
+  const windows = await self.clients.matchAll({ type: 'window', includeUncontrolled: true });
+  const client = windows.find(c => c.url === url);
+  if (client !== undefined) return client.focus();
+  return self.clients.openWindow(url);
+}
@@ -9,7 +9,7 @@ ARCH=$3
 DEB_SRC="deb-${VERSION}-${ARCH}"
 DEB_NAME="${NAME}_${VERSION}_${ARCH}.deb"
 
-for bin in push-server push-send push-keygen; do
+for bin in push-server push-send push-keygen push-admin; do
   install -Dm755 "${BIN_PATH}/${bin}" "${DEB_SRC}/usr/bin/${bin}"
   install -Dm644 "deb/${bin}.7" "${DEB_SRC}/usr/share/man/man7/${bin}.7"
   gzip -n --best "${DEB_SRC}/usr/share/man/man7/${bin}.7"
@@ -0,0 +1,21 @@
+CREATE TABLE message (
+    id INTEGER PRIMARY KEY,
+    content BLOB NOT NULL,
+    ttl INTEGER NOT NULL,
+    inserted DATE DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+-- status is one of 'pending', 'delivered', 'rejected' or 'failed'
+CREATE TABLE delivery (
+    id INTEGER PRIMARY KEY,
+    message_id INTEGER NOT NULL REFERENCES message(id) ON DELETE CASCADE,
+    subscription_id INTEGER NOT NULL REFERENCES subscription(id) ON DELETE CASCADE,
+    status TEXT DEFAULT 'pending' NOT NULL,
+    attempts INTEGER DEFAULT 0 NOT NULL,
+    response_status INTEGER,
+    error TEXT,
+    updated DATE DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    UNIQUE (message_id, subscription_id)
+);
+
+CREATE INDEX delivery_status ON delivery(status);
@@ -0,0 +1,11 @@
+CREATE TABLE topic (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    inserted DATE DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE TABLE subscription_topic (
+    subscription_id INTEGER NOT NULL REFERENCES subscription(id) ON DELETE CASCADE,
+    topic_id INTEGER NOT NULL REFERENCES topic(id) ON DELETE CASCADE,
+    PRIMARY KEY (subscription_id, topic_id)
+);
@@ -0,0 +1,2 @@
+-- rfc8030 Topic header, unrelated to the topic table
+ALTER TABLE message ADD COLUMN topic TEXT;
@@ -0,0 +1,2 @@
+-- comma separated content encodings supported by the user agent, aes128gcm if NULL
+ALTER TABLE subscription ADD COLUMN content_encodings TEXT;
@@ -1,21 +1,24 @@
+use msg::Input;
 use pusher::base64::base64url_decode;
 use pusher::err::Result;
 use pusher::err_other;
+use pusher::msg::{Msg, NotificationOptions};
+use pusher::push::{PushOptions, Sender, Target};
 use pusher::utils::{get_var, to_array};
-use req::VapidConfig;
 use server::run;
 use std::env;
 use std::path::PathBuf;
+use url::Url;
 
 mod msg;
-mod req;
 mod server;
 
 pub struct Config {
-    pub title: String,
+    /// The message that the input is sent as, unless given otherwise
+    pub defaults: Input,
     pub encryption_key: [u8; 16],
     pub db_path: String,
-    pub vapid_conf: VapidConfig,
+    pub sender: Sender,
     pub push_test_addr: PathBuf,
     pub mode: Mode,
 }
@@ -29,12 +32,41 @@ impl Config {
     pub fn from_env() -> Result<Self> {
         let mut args = env::args();
         let progname = args.next().ok_or("invalid args")?;
-        let (mode, title) = match (args.next(), args.next()) {
-            (Some(s0), Some(s1)) if s0 == "--server" => (Mode::Server, s1),
-            (Some(s0), Some(s1)) if s1 == "--server" => (Mode::Server, s0),
-            (Some(s), None) => (Mode::Single, s),
-            _ => return Err(format!("usage: {progname} [--server] title").into()),
-        };
+        let usage = format!(
+            "usage: {progname} [--server] [--to name]... [--topic topic] [--ttl seconds] \
+            [--urgency urgency] [--replace-topic topic] [--padding padding] [--options json] [--url url] [--truncate] title"
+        );
+        let mut mode = Mode::Single;
+        let mut target = Target::default();
+        let mut opts = PushOptions::default();
+        let mut options = NotificationOptions::default();
+        let mut url = None;
+        let mut truncate = false;
+        let mut title = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--server" => mode = Mode::Server,
+                "--truncate" => truncate = true,
+                "--to" => target.names.push(args.next().ok_or(usage.as_str())?),
+                "--topic" => target.topic = Some(args.next().ok_or(usage.as_str())?),
+                "--ttl" => {
+                    let ttl = args.next().ok_or(usage.as_str())?;
+                    opts.ttl = err_other!(ttl.parse(), "invalid ttl '{ttl}'")?;
+                }
+                "--urgency" => opts.urgency = Some(args.next().ok_or(usage.as_str())?.parse()?),
+                "--replace-topic" => opts.topic = Some(args.next().ok_or(usage.as_str())?.parse()?),
+                "--padding" => opts.padding = Some(args.next().ok_or(usage.as_str())?.parse()?),
+                "--options" => options = serde_json::from_str(&args.next().ok_or(usage.as_str())?)?,
+                "--url" => {
+                    let arg = args.next().ok_or(usage.as_str())?;
+                    url = Some(err_other!(Url::parse(&arg), "invalid url '{arg}'")?);
+                }
+                s if s.starts_with("--") => return Err(usage.into()),
+                _ if title.is_none() => title = Some(arg),
+                _ => return Err(usage.into()),
+            }
+        }
+        let title = title.ok_or(usage.as_str())?;
         let encryption_key = get_var("DATABASE_ENCRYPTION_KEY")
             .and_then(base64url_decode)
             .and_then(to_array)?;
@@ -43,12 +75,23 @@ impl Config {
             get_var("PUSH_SOCKET_ADDR")?.parse(),
             "invalid PUSH_SOCKET_ADDR"
         )?;
-        let vapid_conf = VapidConfig::from_env()?;
+        let sender = Sender::from_env()?;
+        let defaults = Input {
+            msg: Msg {
+                title,
+                body: String::new(),
+                url,
+                options,
+            },
+            target,
+            opts,
+            truncate,
+        };
         Ok(Self {
-            title,
+            defaults,
             encryption_key,
             db_path,
-            vapid_conf,
+            sender,
             push_test_addr,
             mode,
         })
@@ -1,84 +1,190 @@
-use pusher::err::{Error, Result};
-use serde::Serialize;
+use pusher::err::Result;
+use pusher::msg::{Msg, NotificationOptions};
+use pusher::push::{Padding, PushOptions, PushTopic, Target, Urgency};
+use serde::Deserialize;
 use std::io;
 use std::io::Read;
 use tokio::io::AsyncReadExt;
 use tokio::net::UnixStream;
+use url::Url;
 
-const ICON: &str = "push-small.png";
-
-#[derive(Debug)]
-pub struct Msg {
-    title: String,
-    body: String,
+/// A message along with the subscriptions it is sent to and how it is delivered
+#[derive(Clone, Debug)]
+pub struct Input {
+    pub msg: Msg,
+    pub target: Target,
+    pub opts: PushOptions,
+    /// Truncate the body if the message does not fit into a push message
+    pub truncate: bool,
 }
 
-impl Serialize for Msg {
-    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        #[derive(Serialize)]
-        struct MsgRaw<'a> {
-            title: &'a str,
-            options: MsgOpt<'a>,
+impl Input {
+    /// The message as the content of a push message
+    pub fn content(&self) -> Result<Vec<u8>> {
+        match self.truncate {
+            true => {
+                let mut msg = self.msg.clone();
+                msg.truncate_body()?;
+                Vec::try_from(&msg)
+            }
+            false => Vec::try_from(&self.msg),
         }
-        #[derive(Serialize)]
-        pub struct MsgOpt<'a> {
-            body: &'a str,
-            icon: &'static str,
-        }
-        let raw = MsgRaw {
-            title: &self.title,
-            options: MsgOpt {
-                body: &self.body,
-                icon: ICON,
-            },
-        };
-        raw.serialize(serializer)
     }
 }
 
-impl Msg {
-    /// Read message body from the stream
-    pub async fn from_stream(mut stream: UnixStream, title: String) -> Result<Self> {
-        let mut body = String::new();
-        stream.read_to_string(&mut body).await?;
-        Ok(Self { title, body })
-    }
+/// Input written into the socket as a JSON object instead of the plain message body
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SocketInput {
+    body: String,
+    /// Names or glob patterns of the subscriptions to send the message to
+    #[serde(default)]
+    to: Vec<String>,
+    topic: Option<String>,
+    ttl: Option<usize>,
+    urgency: Option<Urgency>,
+    /// Replaces a pending message with the same topic (rfc8030 section 5.4)
+    replace_topic: Option<PushTopic>,
+    padding: Option<Padding>,
+    options: Option<NotificationOptions>,
+    /// Opened when the notification is clicked
+    url: Option<Url>,
+}
 
-    /// Read message body from [io::stdin()]
-    pub fn from_stdin(title: String) -> Result<Self> {
-        let mut body = String::new();
-        io::stdin().read_to_string(&mut body).unwrap();
-        Ok(Self { title, body })
+/// Parse the socket input into a message, the fields given in the input override the
+/// ones in `defaults`. Input that is not a JSON object with a body is used as the message
+/// body as is, while an invalid [SocketInput] is an error.
+fn parse_socket_input(input: String, defaults: &Input) -> Result<Input> {
+    let mut msg = defaults.clone();
+    let is_object_with_body =
+        serde_json::from_str::<serde_json::Value>(&input).is_ok_and(|v| v.get("body").is_some());
+    if !is_object_with_body {
+        msg.msg.body = input;
+        return Ok(msg);
+    }
+    let input: SocketInput = serde_json::from_str(&input)?;
+    msg.msg.body = input.body;
+    if let Some(options) = input.options {
+        msg.msg.options = options;
+    }
+    if let Some(url) = input.url {
+        msg.msg.url = Some(url);
     }
+    let target = Target {
+        names: input.to,
+        topic: input.topic,
+    };
+    if !target.is_all() {
+        msg.target = target;
+    }
+    msg.opts = PushOptions {
+        ttl: input.ttl.unwrap_or(defaults.opts.ttl),
+        urgency: input.urgency.or(defaults.opts.urgency),
+        topic: input.replace_topic.or_else(|| defaults.opts.topic.clone()),
+        padding: input.padding.or(defaults.opts.padding),
+    };
+    Ok(msg)
 }
 
-impl TryFrom<Msg> for Vec<u8> {
-    type Error = Error;
+/// Read the message from the stream, the target and options not given in it default to
+/// the ones in `defaults`
+pub async fn from_stream(mut stream: UnixStream, defaults: &Input) -> Result<Input> {
+    let mut input = String::new();
+    stream.read_to_string(&mut input).await?;
+    parse_socket_input(input, defaults)
+}
 
-    /// Turn the message into JSON and serialize the result as utf-8 bytes.
-    fn try_from(msg: Msg) -> Result<Self> {
-        Ok(serde_json::to_string(&msg)?.as_bytes().to_vec())
-    }
+/// Read message body from [io::stdin()]
+pub fn from_stdin(defaults: &Input) -> Result<Input> {
+    let mut msg = defaults.clone();
+    io::stdin().read_to_string(&mut msg.msg.body)?;
+    Ok(msg)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use pusher::msg::Direction;
+
+    fn defaults() -> Input {
+        Input {
+            msg: Msg::new(String::from("title"), String::new()),
+            target: Target::default(),
+            opts: PushOptions::default(),
+            truncate: false,
+        }
+    }
 
     #[test]
-    fn to_vec_works() {
-        let msg = Msg {
-            title: String::from("title 1"),
-            body: String::from("this is a body"),
-        };
-        let content = Vec::try_from(msg).unwrap();
-
-        let content_exp1 = format!(
-            r#"{{"title":"title 1","options":{{"body":"this is a body","icon":"{ICON}"}}}}"#
-        );
-        assert!(content == content_exp1.as_bytes())
+    fn socket_input_can_be_plain_body() {
+        let input = parse_socket_input(String::from("just a body"), &defaults()).unwrap();
+        assert_eq!(input.msg.title, "title");
+        assert_eq!(input.msg.body, "just a body");
+        assert!(input.target.is_all());
+        assert_eq!(input.opts, PushOptions::default());
+
+        let input = parse_socket_input(String::from(r#"{"other":"json"}"#), &defaults()).unwrap();
+        assert_eq!(input.msg.body, r#"{"other":"json"}"#);
+        assert!(input.target.is_all());
+    }
+
+    #[test]
+    fn socket_input_can_have_target() {
+        let input = r#"{"body":"a body","to":["kitchen-tablet","phone-*"]}"#;
+        let input = parse_socket_input(String::from(input), &defaults()).unwrap();
+        assert_eq!(input.msg.body, "a body");
+        assert_eq!(input.target.names, ["kitchen-tablet", "phone-*"]);
+        assert_eq!(input.target.topic, None);
+
+        let input = r#"{"body":"a body","topic":"backups"}"#;
+        let input = parse_socket_input(String::from(input), &defaults()).unwrap();
+        assert!(input.target.names.is_empty());
+        assert_eq!(input.target.topic.as_deref(), Some("backups"));
+    }
+
+    #[test]
+    fn socket_input_can_override_options() {
+        let mut defaults = defaults();
+        defaults.opts.ttl = 60;
+        defaults.opts.urgency = Some(Urgency::Low);
+        defaults.target.names = vec![String::from("phone")];
+        let input =
+            r#"{"body":"a body","urgency":"high","replace_topic":"status","padding":"pad-to-max"}"#;
+        let input = parse_socket_input(String::from(input), &defaults).unwrap();
+        assert_eq!(input.opts.padding, Some(Padding::PadToMax));
+        assert_eq!(input.opts.ttl, 60);
+        assert_eq!(input.opts.urgency, Some(Urgency::High));
+        assert_eq!(input.opts.topic, Some("status".parse().unwrap()));
+        assert_eq!(input.target.names, ["phone"]);
+
+        let input = r#"{"body":"a body","urgency":"now"}"#;
+        assert!(parse_socket_input(String::from(input), &defaults).is_err());
+        let input = r#"{"body":"a body","replace_topic":"not/base64url"}"#;
+        assert!(parse_socket_input(String::from(input), &defaults).is_err());
+    }
+
+    #[test]
+    fn long_body_can_be_truncated() {
+        let mut input = defaults();
+        input.msg.body = "a".repeat(5000);
+        assert!(input.content().is_err());
+        input.truncate = true;
+        let content = String::from_utf8(input.content().unwrap()).unwrap();
+        assert!(content.contains("aaa…"));
+    }
+
+    #[test]
+    fn socket_input_can_have_notification_options() {
+        let input = r#"{"body":"a body","options":{"tag":"disk","dir":"rtl"}}"#;
+        let input = parse_socket_input(String::from(input), &defaults()).unwrap();
+        assert_eq!(input.msg.options.tag.as_deref(), Some("disk"));
+        assert_eq!(input.msg.options.dir, Some(Direction::Rtl));
+
+        let input = r#"{"body":"a body","url":"https://example.com/disk"}"#;
+        let input = parse_socket_input(String::from(input), &defaults()).unwrap();
+        assert_eq!(input.msg.url.unwrap().as_str(), "https://example.com/disk");
+
+        let input = r#"{"body":"a body","options":{"body":"other"}}"#;
+        assert!(parse_socket_input(String::from(input), &defaults()).is_err());
     }
 }
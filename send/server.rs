@@ -1,11 +1,12 @@
-use crate::msg::Msg;
-use crate::req::send_notifications;
+use crate::msg::{from_stdin, from_stream};
 use crate::{Config, Mode};
-use pusher::db::get_pool;
+use deadpool_sqlite::Pool;
+use pusher::db::{check_schema, get_pool};
+use pusher::delivery::Summary;
 use pusher::err::Result;
 use std::path::Path;
 use tokio::fs;
-use tokio::net::UnixListener;
+use tokio::net::{UnixListener, UnixStream};
 use tracing::Level;
 
 async fn get_listener(path: &Path) -> Result<UnixListener> {
@@ -15,43 +16,77 @@ async fn get_listener(path: &Path) -> Result<UnixListener> {
     Ok(UnixListener::bind(path)?)
 }
 
+/// Read a message from the `stream` and send it to the subscribed clients. The target and
+/// options given in the message take precedence over the ones in [Config].
+async fn forward(config: &Config, pool: &Pool, stream: UnixStream) -> Result<Summary> {
+    let input = from_stream(stream, &config.defaults).await?;
+    let content = input.content()?;
+    config
+        .sender
+        .send_notifications(
+            pool,
+            &content,
+            &input.opts,
+            &input.target,
+            config.encryption_key,
+        )
+        .await
+}
+
 /// Listen for connections to the socket specified in [Config] and forward the socket
-/// input as a push message to all subscribed clients.
+/// input as a push message to all subscribed clients. Deliveries left pending by an earlier
+/// run are resumed first. Errors are logged so that a failing message does not stop the server.
 pub async fn listen(config: Config) -> Result<()> {
     let listener = get_listener(&config.push_test_addr).await?;
-    let pool = get_pool(&config.db_path, true)?;
+    let pool = get_pool(&config.db_path, false)?;
+    check_schema(&pool).await?;
+    if let Err(e) = config
+        .sender
+        .drain(&pool, config.encryption_key, None)
+        .await
+    {
+        tracing::error!("Resuming pending deliveries failed: {e}");
+    }
     let mut i = 0;
     while let Ok((stream, _addr)) = listener.accept().await {
         let span = tracing::span!(Level::INFO, "msg_ind", i);
         let _enter = span.enter();
-        let content = Msg::from_stream(stream, config.title.clone())
-            .await
-            .and_then(Vec::try_from)?;
-        send_notifications(
-            &pool,
-            &config.vapid_conf,
-            &content,
-            10,
-            config.encryption_key,
-        )
-        .await?;
+        if let Err(e) = forward(&config, &pool, stream).await {
+            tracing::error!("{e}");
+        }
         i += 1;
     }
     Ok(())
 }
 
 pub async fn msg_from_stdin(config: Config) -> Result<()> {
-    let pool = get_pool(&config.db_path, true)?;
-    let content = Msg::from_stdin(config.title).and_then(Vec::try_from)?;
-    send_notifications(
-        &pool,
-        &config.vapid_conf,
-        &content,
-        10,
-        config.encryption_key,
-    )
-    .await?;
-    Ok(())
+    let pool = get_pool(&config.db_path, false)?;
+    check_schema(&pool).await?;
+    let input = from_stdin(&config.defaults)?;
+    let content = input.content()?;
+    // as in listen, so that the deliveries interrupted earlier are not left pending when
+    // push-send is not run as a server
+    if let Err(e) = config
+        .sender
+        .drain(&pool, config.encryption_key, None)
+        .await
+    {
+        tracing::error!("Resuming pending deliveries failed: {e}");
+    }
+    let summary = config
+        .sender
+        .send_notifications(
+            &pool,
+            &content,
+            &input.opts,
+            &input.target,
+            config.encryption_key,
+        )
+        .await?;
+    match summary.failed() {
+        0 => Ok(()),
+        n => Err(format!("delivery failed for {n} subscriptions").into()),
+    }
 }
 
 #[tokio::main]
@@ -0,0 +1,322 @@
+use axum::extract::{Path, Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::StatusCode;
+use axum::middleware::{from_fn_with_state, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use deadpool_sqlite::Pool;
+use openssl::memcmp;
+use pusher::delivery::Summary;
+use pusher::err::{Error, Result};
+use pusher::err_to_resp;
+use pusher::msg::{Msg, NotificationOptions};
+use pusher::push::{Padding, PushOptions, PushTopic, Sender, Target, Urgency};
+use pusher::subscription::{find_subscriptions, SubscriptionRef};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use url::Url;
+
+/// Everything needed for sending messages from within push-server
+#[derive(Clone)]
+pub struct SenderState {
+    pub pool: Pool,
+    pub encryption_key: [u8; 16],
+    pub sender: Arc<Sender>,
+}
+
+impl SenderState {
+    /// Send the `content` of the message, see [ApiMessage::content], to the targeted
+    /// subscriptions
+    pub async fn send(&self, content: &[u8], msg: &ApiMessage) -> Result<Summary> {
+        self.sender
+            .send_notifications(
+                &self.pool,
+                content,
+                &msg.options(),
+                &msg.target(),
+                self.encryption_key,
+            )
+            .await
+    }
+}
+
+#[derive(Clone)]
+pub struct ApiState {
+    pub token: Arc<str>,
+    pub sender: SenderState,
+    /// Title of the diagnostic messages
+    pub test_title: Arc<str>,
+}
+
+/// A message sent through the API
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiMessage {
+    title: String,
+    #[serde(default)]
+    body: String,
+    /// Options passed to `showNotification`
+    #[serde(default)]
+    options: NotificationOptions,
+    /// Same as `options.icon`, kept for the clients that set it at the top level
+    icon: Option<String>,
+    /// Same as `options.tag`, kept for the clients that set it at the top level
+    tag: Option<String>,
+    /// Opened when the notification is clicked
+    url: Option<Url>,
+    /// Truncate the body if the message does not fit into a push message
+    #[serde(default)]
+    truncate: bool,
+    ttl: Option<usize>,
+    urgency: Option<Urgency>,
+    /// Replaces a pending message with the same topic (rfc8030 section 5.4)
+    replace_topic: Option<PushTopic>,
+    /// The padding of the sender (PUSH_PADDING) is used if not set
+    padding: Option<Padding>,
+    /// Names or glob patterns of the subscriptions to send the message to, all of them if empty
+    #[serde(default)]
+    target: Vec<String>,
+    /// Only the subscribers of the topic receive the message, if set
+    topic: Option<String>,
+}
+
+impl ApiMessage {
+    pub fn new(title: String, body: String) -> Self {
+        Self {
+            title,
+            body,
+            ..Default::default()
+        }
+    }
+
+    /// The message as a JSON notification that the service worker passes to `showNotification`
+    pub fn content(&self) -> Result<Vec<u8>> {
+        let mut msg = Msg {
+            title: self.title.clone(),
+            body: self.body.clone(),
+            url: self.url.clone(),
+            options: self.notification_options()?,
+        };
+        if self.truncate {
+            msg.truncate_body()?;
+        }
+        Vec::try_from(&msg)
+    }
+
+    /// The `options` with the top-level `icon` and `tag` merged into them
+    fn notification_options(&self) -> Result<NotificationOptions> {
+        let mut options = self.options.clone();
+        if let Some(icon) = &self.icon {
+            // options.icon is set unless it is the default
+            if options.icon != NotificationOptions::default().icon {
+                return Err("icon is set both in the message and in its options".into());
+            }
+            options.icon = Some(icon.clone());
+        }
+        if let Some(tag) = &self.tag {
+            if options.tag.is_some() {
+                return Err("tag is set both in the message and in its options".into());
+            }
+            options.tag = Some(tag.clone());
+        }
+        Ok(options)
+    }
+
+    fn target(&self) -> Target {
+        Target {
+            names: self.target.clone(),
+            topic: self.topic.clone(),
+        }
+    }
+
+    fn options(&self) -> PushOptions {
+        let default = PushOptions::default();
+        PushOptions {
+            ttl: self.ttl.unwrap_or(default.ttl),
+            urgency: self.urgency,
+            topic: self.replace_topic.clone(),
+            padding: self.padding,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SendResult {
+    delivered: usize,
+    rejected: usize,
+    failed: usize,
+    pruned: usize,
+}
+
+impl From<&Summary> for SendResult {
+    fn from(summary: &Summary) -> Self {
+        Self {
+            delivered: summary.delivered(),
+            rejected: summary.rejected(),
+            failed: summary.failed(),
+            pruned: summary.pruned,
+        }
+    }
+}
+
+pub fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/messages", post(send_message))
+        .route("/subscriptions/{subscription}/test-push", post(test_push))
+        .route_layer(from_fn_with_state(state.clone(), authorize))
+        .with_state(state)
+}
+
+/// Require `Authorization: Bearer <token>` with the configured token
+async fn authorize(State(state): State<ApiState>, req: Request, next: Next) -> Response {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    match token {
+        Some(t)
+            if t.len() == state.token.len() && memcmp::eq(t.as_bytes(), state.token.as_bytes()) =>
+        {
+            next.run(req).await
+        }
+        _ => {
+            tracing::info!("Unauthorized API request");
+            StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+}
+
+/// Send the message to the subscriptions and return the number of deliveries per outcome
+async fn send_message(State(state): State<ApiState>, Json(msg): Json<ApiMessage>) -> Response {
+    tracing::info!("API MESSAGE {}", msg.title);
+    let content = match msg.content() {
+        Err(e @ Error::PayloadTooLarge { .. }) => {
+            return (StatusCode::PAYLOAD_TOO_LARGE, e.to_string()).into_response()
+        }
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        Ok(content) => content,
+    };
+    let summary = err_to_resp!(state.sender.send(&content, &msg).await);
+    (StatusCode::OK, Json(SendResult::from(&summary))).into_response()
+}
+
+/// Send a diagnostic message to a single subscription, given by id or name, and return the
+/// response of the push service
+async fn test_push(State(state): State<ApiState>, Path(sub): Path<String>) -> Response {
+    let sub: SubscriptionRef = match sub.parse() {
+        Ok(sub) => sub,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    tracing::info!("API TEST PUSH {sub}");
+    let SenderState {
+        pool,
+        encryption_key,
+        sender,
+    } = &state.sender;
+    let subs = err_to_resp!(find_subscriptions(pool, *encryption_key, sub.clone()).await);
+    let sub = match subs.as_slice() {
+        [] => {
+            let msg = format!("no subscription with {sub}");
+            return (StatusCode::NOT_FOUND, msg).into_response();
+        }
+        [sub] => sub,
+        _ => {
+            let msg = format!("several subscriptions with {sub}, use the id");
+            return (StatusCode::CONFLICT, msg).into_response();
+        }
+    };
+    match sender.send_diagnostic(sub, &state.test_title).await {
+        Ok(resp) => (StatusCode::OK, Json(resp)).into_response(),
+        Err(e) => {
+            tracing::error!("Test push to {} failed: {e}", sub.name());
+            (StatusCode::BAD_GATEWAY, e.to_string()).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pusher::msg::ICON;
+
+    #[test]
+    fn content_works() {
+        let msg: ApiMessage = serde_json::from_str(
+            r#"{"title":"backup","body":"done","url":"https://example.com/backups","options":{"tag":"b"}}"#,
+        )
+        .unwrap();
+        let content = String::from_utf8(msg.content().unwrap()).unwrap();
+        let content_exp = format!(
+            r#"{{"title":"backup","options":{{"body":"done","icon":"{ICON}","tag":"b","data":{{"url":"https://example.com/backups"}}}}}}"#
+        );
+        assert_eq!(content, content_exp);
+
+        // icon and tag at the top level, as before the options
+        let msg: ApiMessage = serde_json::from_str(
+            r#"{"title":"backup","body":"done","url":"https://example.com/backups","tag":"b"}"#,
+        )
+        .unwrap();
+        let content = String::from_utf8(msg.content().unwrap()).unwrap();
+        assert_eq!(content, content_exp);
+        let msg: ApiMessage =
+            serde_json::from_str(r#"{"title":"t","icon":"i.png","options":{"badge":"b.png"}}"#)
+                .unwrap();
+        let content = String::from_utf8(msg.content().unwrap()).unwrap();
+        let content_exp = r#"{"title":"t","options":{"body":"","icon":"i.png","badge":"b.png"}}"#;
+        assert_eq!(content, content_exp);
+        for json in [
+            r#"{"title":"t","tag":"a","options":{"tag":"b"}}"#,
+            r#"{"title":"t","icon":"a.png","options":{"icon":"b.png"}}"#,
+            r#"{"title":"t","icon":"a.png","options":{"icon":null}}"#,
+        ] {
+            let msg: ApiMessage = serde_json::from_str(json).unwrap();
+            assert!(msg.content().is_err(), "{json}");
+        }
+
+        let msg: ApiMessage = serde_json::from_str(
+            r#"{"title":"t","url":"https://example.com/","options":{"icon":null,"data":{"id":1}}}"#,
+        )
+        .unwrap();
+        let content = String::from_utf8(msg.content().unwrap()).unwrap();
+        let content_exp =
+            r#"{"title":"t","options":{"body":"","data":{"id":1,"url":"https://example.com/"}}}"#;
+        assert_eq!(content, content_exp);
+
+        let msg: ApiMessage =
+            serde_json::from_str(r#"{"title":"t","options":{"renotify":true}}"#).unwrap();
+        assert!(msg.content().is_err());
+
+        let mut msg = ApiMessage::new(String::from("t"), "a".repeat(5000));
+        assert!(matches!(msg.content(), Err(Error::PayloadTooLarge { .. })));
+        msg.truncate = true;
+        assert!(msg.content().is_ok());
+    }
+
+    #[test]
+    fn options_are_validated() {
+        let msg: ApiMessage =
+            serde_json::from_str(r#"{"title":"t","ttl":60,"urgency":"low","target":["a"]}"#)
+                .unwrap();
+        let opts = msg.options();
+        assert_eq!(opts.ttl, 60);
+        assert_eq!(opts.urgency, Some(Urgency::Low));
+        assert_eq!(msg.target().names, ["a"]);
+        assert_eq!(msg.target().topic, None);
+        assert_eq!(opts.topic, None);
+
+        let msg: ApiMessage = serde_json::from_str(
+            r#"{"title":"t","replace_topic":"disk-usage","padding":"pad-to-bucket"}"#,
+        )
+        .unwrap();
+        assert_eq!(msg.options().topic, Some("disk-usage".parse().unwrap()));
+        assert_eq!(msg.options().padding, Some(Padding::PadToBucket));
+
+        assert!(serde_json::from_str::<ApiMessage>(r#"{"title":"t","urgency":"now"}"#).is_err());
+        assert!(
+            serde_json::from_str::<ApiMessage>(r#"{"title":"t","replace_topic":"a b"}"#).is_err()
+        );
+        assert!(serde_json::from_str::<ApiMessage>(r#"{"title":"t","extra":1}"#).is_err());
+    }
+}
@@ -2,9 +2,12 @@ use crate::vapid::PublicKey;
 use pusher::base64::base64url_decode;
 use pusher::err::Result;
 use pusher::err_other;
-use pusher::utils::{get_var, to_array};
+use pusher::push::Sender;
+use pusher::utils::{get_var, parse_var_or, to_array};
+use std::env;
 use std::net::SocketAddr;
 
+mod api;
 mod server;
 mod trigger_push;
 mod vapid;
@@ -15,6 +18,9 @@ pub struct Config {
     pub encryption_key: [u8; 16],
     pub db_path: String,
     pub push_test_addr: Option<String>,
+    pub push_test_title: String,
+    pub sender: Option<Sender>,
+    pub api_token: Option<String>,
 }
 
 impl Config {
@@ -27,21 +33,43 @@ impl Config {
             .and_then(to_array)?;
         let db_path = get_var("DATABASE_PATH")?;
         let push_test_addr = get_var("PUSH_SOCKET_ADDR").ok();
+        let push_test_title = parse_var_or("PUSH_TEST_TITLE", String::from("pusher"))?;
+        // messages are sent in-process only if the private key is available
+        let sender = match get_var("VAPID_PRIVATE_KEY") {
+            Ok(_) => Some(Sender::from_env()?),
+            Err(_) => None,
+        };
+        let api_token = get_var("API_TOKEN").ok();
+        match &api_token {
+            Some(t) if t.is_empty() => return Err("API_TOKEN must not be empty".into()),
+            Some(_) if sender.is_none() => {
+                return Err("API_TOKEN requires VAPID_PRIVATE_KEY and VAPID_SUBJECT".into())
+            }
+            _ => {}
+        }
         Ok(Self {
             pubkey,
             listen_addr,
             encryption_key,
             db_path,
             push_test_addr,
+            push_test_title,
+            sender,
+            api_token,
         })
     }
 }
 
 fn main() {
-    Config::from_env()
-        .and_then(server::run)
-        .unwrap_or_else(|e| {
-            eprintln!("{e}");
-            std::process::exit(1)
-        });
+    let args: Vec<_> = env::args().collect();
+    let res = match args.as_slice() {
+        [_] => Config::from_env().and_then(server::run),
+        [_, arg] if arg == "--migrate-only" => get_var("DATABASE_PATH").and_then(server::migrate),
+        [progname, ..] => Err(format!("usage: {progname} [--migrate-only]").into()),
+        [] => Err("invalid args".into()),
+    };
+    res.unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1)
+    });
 }
@@ -1,10 +1,13 @@
-use crate::trigger_push::{socket_exists, write_to_socket};
-use crate::{vapid, Config};
+use crate::api::{ApiState, SenderState};
+use crate::trigger_push::{socket_exists, test_push, TestPush};
+use crate::{api, vapid, Config};
 use axum::response::{Redirect, Response};
 use axum::routing::{delete, get, post};
-use pusher::db::get_pool;
+use pusher::db::{get_pool, run_migrations};
 use pusher::err::Result;
 use pusher::subscription::{subscribe, unsubscribe};
+use pusher::topic::{list_topics, subscription_topics, update_topics};
+use std::sync::Arc;
 use tokio::net::TcpListener;
 use tower_http::services::ServeDir;
 use tower_http::trace::{DefaultMakeSpan, TraceLayer};
@@ -17,6 +20,28 @@ fn log_status<B, D, S>(response: &Response<B>, _latency: D, _span: &S) {
     }
 }
 
+/// Deliver the messages left pending by an earlier run. This runs alongside the handlers,
+/// which do not send the same deliveries as they are claimed by one drain only.
+async fn resume_deliveries(state: SenderState) {
+    let drained = state
+        .sender
+        .drain(&state.pool, state.encryption_key, None)
+        .await;
+    if let Err(e) = drained {
+        tracing::error!("Resuming pending deliveries failed: {e}");
+    }
+}
+
+/// Bring the database schema up to date without starting the server
+#[tokio::main]
+pub async fn migrate(db_path: String) -> Result<()> {
+    let pool = get_pool(&db_path, false)?;
+    for name in run_migrations(&pool).await? {
+        println!("Applied {name}");
+    }
+    Ok(())
+}
+
 #[tokio::main]
 pub async fn run(conf: Config) -> Result<()> {
     tracing_subscriber::fmt::fmt()
@@ -24,26 +49,54 @@ pub async fn run(conf: Config) -> Result<()> {
         .init();
 
     let pool = get_pool(&conf.db_path, false)?;
+    for name in run_migrations(&pool).await? {
+        tracing::info!("Applied migration {name}");
+    }
 
     let trace = TraceLayer::new_for_http()
         .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
         .on_response(log_status);
-    let tmp_path = conf.push_test_addr.map(|s| s.into());
-    let tmp_path_exists = tmp_path.is_some();
+    let sender = conf.sender.map(|sender| SenderState {
+        pool: pool.clone(),
+        encryption_key: conf.encryption_key,
+        sender: Arc::new(sender),
+    });
+    if let Some(sender) = sender.clone() {
+        tokio::spawn(resume_deliveries(sender));
+    }
+    let test_title: Arc<str> = conf.push_test_title.into();
+    let test_push_state = match (&sender, conf.push_test_addr) {
+        (Some(sender), _) => TestPush::InProcess(sender.clone(), test_title.clone()),
+        (None, Some(addr)) => TestPush::Socket(addr.into()),
+        (None, None) => TestPush::Disabled,
+    };
+    let api_state = conf.api_token.zip(sender).map(|(token, sender)| ApiState {
+        token: token.into(),
+        sender,
+        test_title,
+    });
 
     let app = axum::Router::new()
         .nest("/vapid", vapid::router())
         .with_state(conf.pubkey)
         .route("/subscribe", post(subscribe))
         .route("/unsubscribe", delete(unsubscribe))
+        .route("/topics", get(list_topics))
+        .route(
+            "/subscription/topics",
+            get(subscription_topics).put(update_topics),
+        )
         .with_state((pool, conf.encryption_key))
         .route("/test-push/info", get(socket_exists))
-        .with_state(tmp_path_exists)
-        .route("/test-push", post(write_to_socket))
-        .with_state(tmp_path)
+        .route("/test-push", post(test_push))
+        .with_state(test_push_state)
         .route("/", get(Redirect::to("/index.html")))
-        .fallback_service(ServeDir::new("assets"))
-        .layer(trace);
+        .fallback_service(ServeDir::new("assets"));
+    let app = match api_state {
+        Some(state) => app.nest("/api", api::router(state)),
+        None => app,
+    }
+    .layer(trace);
 
     tracing::info!("listening on {}", conf.listen_addr);
 
@@ -1,3 +1,4 @@
+use crate::api::{ApiMessage, SenderState};
 use axum::extract::State;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
@@ -12,21 +13,33 @@ pub struct Message {
     message: String,
 }
 
-/// Write message body into socket, so that `push-sender` can forward it as
-/// a push message. Note that this could be combined into a single binary..?
-pub async fn write_to_socket(
-    State(push_test_addr): State<Option<Arc<str>>>,
-    Json(msg): Json<Message>,
-) -> Response {
-    let Some(push_test_addr) = push_test_addr else {
-        tracing::info!("Trying to write without socket addr");
-        return StatusCode::OK.into_response();
-    };
-    let mut stream = err_to_resp!(UnixStream::connect(push_test_addr.as_ref()).await);
-
-    err_to_resp!(stream.write_all(msg.message.as_bytes()).await);
-    tracing::info!("Wrote to {}", push_test_addr);
+/// How the test messages are sent
+#[derive(Clone)]
+pub enum TestPush {
+    /// Sent by push-server itself, titled with the given title
+    InProcess(SenderState, Arc<str>),
+    /// Written into the socket that `push-send --server` listens to
+    Socket(Arc<str>),
+    Disabled,
+}
 
+/// Send the message body as a push message, either in-process or by writing it into the
+/// socket, so that `push-sender` can forward it.
+pub async fn test_push(State(test_push): State<TestPush>, Json(msg): Json<Message>) -> Response {
+    match test_push {
+        TestPush::InProcess(sender, title) => {
+            let msg = ApiMessage::new(title.to_string(), msg.message);
+            let content = err_to_resp!(msg.content());
+            let summary = err_to_resp!(sender.send(&content, &msg).await);
+            tracing::info!("Sent test push: {summary}");
+        }
+        TestPush::Socket(push_test_addr) => {
+            let mut stream = err_to_resp!(UnixStream::connect(push_test_addr.as_ref()).await);
+            err_to_resp!(stream.write_all(msg.message.as_bytes()).await);
+            tracing::info!("Wrote to {}", push_test_addr);
+        }
+        TestPush::Disabled => tracing::info!("Trying to send a test push while disabled"),
+    }
     StatusCode::OK.into_response()
 }
 
@@ -35,7 +48,8 @@ pub struct SocketExists {
     exists: bool,
 }
 
-/// Check if the socket exists and return it as JSON
-pub async fn socket_exists(State(exists): State<bool>) -> Response {
+/// Check if test messages can be sent and return it as JSON
+pub async fn socket_exists(State(test_push): State<TestPush>) -> Response {
+    let exists = !matches!(test_push, TestPush::Disabled);
     (StatusCode::OK, Json(SocketExists { exists })).into_response()
 }
@@ -1,9 +1,228 @@
 use crate::err::Result;
-use deadpool_sqlite::{Config, Pool, Runtime};
+use crate::err_other;
+use deadpool_sqlite::rusqlite::{Connection, OptionalExtension, TransactionBehavior};
+use deadpool_sqlite::{Config, CreatePoolError, Hook, HookError, Pool, Runtime};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The migrations in `migrations`, in the order they are applied. The file names are
+/// recorded in the `__migrations` table as with `migrations/migrate.sh`.
+const MIGRATIONS: &[(&str, &str)] = &[
+    (
+        "001_subscriptions.sql",
+        include_str!("../migrations/001_subscriptions.sql"),
+    ),
+    (
+        "002_subscriptions_name.sql",
+        include_str!("../migrations/002_subscriptions_name.sql"),
+    ),
+    (
+        "003_delivery.sql",
+        include_str!("../migrations/003_delivery.sql"),
+    ),
+    (
+        "004_message_urgency.sql",
+        include_str!("../migrations/004_message_urgency.sql"),
+    ),
+    (
+        "005_topics.sql",
+        include_str!("../migrations/005_topics.sql"),
+    ),
+    (
+        "006_message_topic.sql",
+        include_str!("../migrations/006_message_topic.sql"),
+    ),
+    (
+        "007_message_padding.sql",
+        include_str!("../migrations/007_message_padding.sql"),
+    ),
+    (
+        "008_subscription_encodings.sql",
+        include_str!("../migrations/008_subscription_encodings.sql"),
+    ),
+];
 
 pub fn get_pool(db_path: &str, read_only: bool) -> Result<Pool> {
-    match read_only {
-        true => Ok(Config::new(format!("file:{db_path}?mode=ro")).create_pool(Runtime::Tokio1)?),
-        false => Ok(Config::new(db_path).create_pool(Runtime::Tokio1)?),
+    let config = match read_only {
+        true => Config::new(format!("file:{db_path}?mode=ro")),
+        false => Config::new(db_path),
+    };
+    let pool = config
+        .builder(Runtime::Tokio1)
+        .map_err(CreatePoolError::Config)?
+        .post_create(foreign_keys_on())
+        .build()
+        .map_err(CreatePoolError::Build)?;
+    Ok(pool)
+}
+
+/// Enable the foreign key constraints on each new connection. SQLite has them off by
+/// default, so the `ON DELETE CASCADE` clauses would do nothing without this.
+fn foreign_keys_on() -> Hook {
+    Hook::async_fn(|conn, _| {
+        Box::pin(async move {
+            conn.interact(|c| c.execute_batch("PRAGMA foreign_keys = ON"))
+                .await
+                .map_err(|e| HookError::Message(e.to_string().into()))?
+                .map_err(HookError::Backend)
+        })
+    })
+}
+
+fn create_migrations_table(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS __migrations(
+            file_name TEXT PRIMARY KEY,
+            succeeded INTEGER NOT NULL
+        ) STRICT;",
+    )?;
+    Ok(())
+}
+
+/// Fail if the database has migrations that this version does not know of, i.e. the schema
+/// is newer than the one this version expects.
+fn check_unknown(conn: &Connection) -> Result<()> {
+    let exists: Option<u32> = conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '__migrations'",
+            [],
+            |r| r.get(0),
+        )
+        .optional()?;
+    if exists.is_none() {
+        return Ok(());
+    }
+    let mut stmt = conn.prepare("SELECT file_name FROM __migrations ORDER BY file_name")?;
+    let applied = stmt.query_map([], |r| r.get::<_, String>(0))?;
+    for name in applied {
+        let name = name?;
+        if !MIGRATIONS.iter().any(|(n, _)| *n == name) {
+            return Err(format!("unknown migration '{name}', the database schema is newer").into());
+        }
+    }
+    Ok(())
+}
+
+/// Apply the pending [MIGRATIONS], each in a transaction of its own, returning the names of
+/// the applied migrations. The write lock is taken before checking whether a migration has
+/// been applied, so that concurrent runs do not apply it twice.
+pub(crate) fn migrate(conn: &mut Connection) -> Result<Vec<&'static str>> {
+    check_unknown(conn)?;
+    create_migrations_table(conn)?;
+    let mut applied = vec![];
+    for (name, sql) in MIGRATIONS {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let done: Option<u32> = tx
+            .query_row(
+                "SELECT 1 FROM __migrations WHERE file_name = ?1",
+                [name],
+                |r| r.get(0),
+            )
+            .optional()?;
+        if done.is_some() {
+            continue;
+        }
+        tx.execute_batch(sql)
+            .map_err(|e| format!("migration '{name}' failed: {e}"))?;
+        let now = err_other!(SystemTime::now().duration_since(UNIX_EPOCH))?;
+        tx.execute(
+            "INSERT INTO __migrations (file_name, succeeded) VALUES (?1, ?2)",
+            (name, now.as_secs()),
+        )?;
+        tx.commit()?;
+        applied.push(*name);
+    }
+    Ok(applied)
+}
+
+/// Bring the database schema up to date, see [migrate].
+pub async fn run_migrations(pool: &Pool) -> Result<Vec<&'static str>> {
+    let conn = pool.get().await?;
+    conn.interact(migrate).await?
+}
+
+/// Fail if the database schema is newer than this version expects.
+pub async fn check_schema(pool: &Pool) -> Result<()> {
+    let conn = pool.get().await?;
+    conn.interact(|c| check_unknown(c)).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table_names(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap();
+        let names = stmt.query_map([], |r| r.get(0)).unwrap();
+        names.map(|n| n.unwrap()).collect()
+    }
+
+    #[test]
+    fn migrations_are_applied_once() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(migrate(&mut conn).unwrap().len(), MIGRATIONS.len());
+        assert!(migrate(&mut conn).unwrap().is_empty());
+        let tables = table_names(&conn);
+        for table in [
+            "__migrations",
+            "delivery",
+            "message",
+            "subscription",
+            "topic",
+        ] {
+            assert!(tables.iter().any(|t| t == table), "{table} missing");
+        }
+        let columns: Vec<String> = conn
+            .prepare("SELECT name FROM pragma_table_info('subscription')")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .map(|c| c.unwrap())
+            .collect();
+        assert!(columns.iter().any(|c| c == "content_encodings"));
+    }
+
+    #[test]
+    fn pending_migrations_are_applied() {
+        // as if migrate.sh had applied the first migrations
+        let mut conn = Connection::open_in_memory().unwrap();
+        create_migrations_table(&conn).unwrap();
+        for (name, sql) in &MIGRATIONS[..3] {
+            conn.execute_batch(sql).unwrap();
+            conn.execute("INSERT INTO __migrations VALUES (?1, 1700000000)", [name])
+                .unwrap();
+        }
+        let applied = migrate(&mut conn).unwrap();
+        let pending: Vec<_> = MIGRATIONS[3..].iter().map(|(n, _)| *n).collect();
+        assert_eq!(applied, pending);
+    }
+
+    #[test]
+    fn failed_migration_is_rolled_back() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        // conflicts with 003_delivery.sql after the message table has been created
+        conn.execute_batch("CREATE TABLE delivery (id INTEGER)")
+            .unwrap();
+        assert!(migrate(&mut conn).is_err());
+        let tables = table_names(&conn);
+        assert!(!tables.iter().any(|t| t == "message"));
+        let applied: u32 = conn
+            .query_row("SELECT COUNT(*) FROM __migrations", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(applied, 2);
+    }
+
+    #[test]
+    fn newer_schema_is_refused() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO __migrations VALUES ('999_future.sql', 1700000000)",
+            [],
+        )
+        .unwrap();
+        assert!(check_unknown(&conn).is_err());
+        assert!(migrate(&mut conn).is_err());
     }
 }
@@ -0,0 +1,146 @@
+use crate::err::Error;
+use crate::queue::DeliveryStatus;
+use reqwest::StatusCode;
+use std::fmt;
+use url::Url;
+
+/// Result of a push message delivery to a single subscription
+#[derive(Debug)]
+pub enum Outcome {
+    /// The push service accepted the message
+    Delivered(StatusCode),
+    /// The push service responded with an error status
+    Rejected(StatusCode),
+    /// The request could not be made or the response could not be read
+    Failed(Error),
+}
+
+impl Outcome {
+    /// The status, response status and error to be recorded in the delivery queue
+    pub fn to_record(&self) -> (DeliveryStatus, Option<u16>, Option<String>) {
+        match self {
+            Outcome::Delivered(status) => (DeliveryStatus::Delivered, Some(status.as_u16()), None),
+            Outcome::Rejected(status) => (DeliveryStatus::Rejected, Some(status.as_u16()), None),
+            Outcome::Failed(e) => (DeliveryStatus::Failed, None, Some(e.to_string())),
+        }
+    }
+}
+
+impl From<StatusCode> for Outcome {
+    fn from(status: StatusCode) -> Self {
+        match status.is_success() {
+            true => Self::Delivered(status),
+            false => Self::Rejected(status),
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Delivered(status) => write!(f, "delivered ({status})"),
+            Outcome::Rejected(status) => write!(f, "rejected ({status})"),
+            Outcome::Failed(e) => write!(f, "failed ({e})"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Delivery {
+    pub name: String,
+    /// [None] if the endpoint of the subscription could not be read
+    pub endpoint: Option<Url>,
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let host = self.endpoint.as_ref().and_then(Url::host_str);
+        let host = host.unwrap_or_default();
+        write!(f, "{} ({host}): {}", self.name, self.outcome)
+    }
+}
+
+/// Results of sending a message to all the subscriptions
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub deliveries: Vec<Delivery>,
+    pub pruned: usize,
+}
+
+impl Summary {
+    pub fn delivered(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Delivered(_)))
+    }
+
+    pub fn rejected(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Rejected(_)))
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Failed(_)))
+    }
+
+    fn count(&self, f: impl Fn(&Outcome) -> bool) -> usize {
+        self.deliveries.iter().filter(|d| f(&d.outcome)).count()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} delivered, {} rejected, {} failed, {} pruned",
+            self.delivered(),
+            self.rejected(),
+            self.failed(),
+            self.pruned
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delivery(outcome: Outcome) -> Delivery {
+        Delivery {
+            name: String::from("sub"),
+            endpoint: Some(Url::parse("https://push.example.com/abc").unwrap()),
+            outcome,
+        }
+    }
+
+    #[test]
+    fn outcome_from_status_works() {
+        assert!(matches!(
+            Outcome::from(StatusCode::CREATED),
+            Outcome::Delivered(_)
+        ));
+        assert!(matches!(
+            Outcome::from(StatusCode::GONE),
+            Outcome::Rejected(_)
+        ));
+    }
+
+    #[test]
+    fn summary_counts_outcomes() {
+        let summary = Summary {
+            deliveries: vec![
+                delivery(Outcome::Delivered(StatusCode::CREATED)),
+                delivery(Outcome::Delivered(StatusCode::CREATED)),
+                delivery(Outcome::Rejected(StatusCode::GONE)),
+                delivery(Outcome::Failed(Error::from("dns failure"))),
+            ],
+            pruned: 1,
+        };
+        assert_eq!(
+            summary.to_string(),
+            "2 delivered, 1 rejected, 1 failed, 1 pruned"
+        );
+        assert_eq!(
+            summary.deliveries[3].to_string(),
+            "sub (push.example.com): failed (dns failure)"
+        );
+    }
+}
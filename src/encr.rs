@@ -1,4 +1,4 @@
-use crate::err::Result;
+use crate::err::{Error, Result};
 use crate::utils::to_array;
 use openssl::hash::MessageDigest;
 use openssl::pkey::PKey;
@@ -6,16 +6,45 @@ use openssl::rand::rand_bytes;
 use openssl::sign::Signer;
 use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
 
-/// FIXME: could this somehow sometimes produce 64 (length off by one)
-pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+/// Size of the output of [hmac_sha256]
+const HASH_LEN: usize = 32;
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<[u8; HASH_LEN]> {
     let key = PKey::hmac(key)?;
-    let dig = Signer::new(MessageDigest::sha256(), &key)?.sign_oneshot_to_vec(data)?;
-    Ok(dig)
+    let mut dig = [0; HASH_LEN];
+    let len = Signer::new(MessageDigest::sha256(), &key)?.sign_oneshot(&mut dig, data)?;
+    match len == HASH_LEN {
+        true => Ok(dig),
+        false => Err(format!("invalid hmac length {len}").into()),
+    }
+}
+
+/// HKDF-Extract with sha256 (rfc5869 section 2.2), an empty salt is replaced with zeros
+pub fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> Result<[u8; HASH_LEN]> {
+    match salt.is_empty() {
+        true => hmac_sha256(&[0; HASH_LEN], ikm),
+        false => hmac_sha256(salt, ikm),
+    }
+}
+
+/// HKDF-Expand with sha256 (rfc5869 section 2.3) into `len` bytes, at most 255 * 32
+pub fn hkdf_expand(prk: &[u8], info: &[u8], len: usize) -> Result<Vec<u8>> {
+    if len > 255 * HASH_LEN {
+        return Err(format!("too long hkdf output {len}").into());
+    }
+    let mut okm = Vec::with_capacity(len);
+    let mut t = vec![];
+    for i in 1..=len.div_ceil(HASH_LEN) as u8 {
+        t = hmac_sha256(prk, &[&t, info, &[i]].concat())?.to_vec();
+        okm.extend_from_slice(&t);
+    }
+    okm.truncate(len);
+    Ok(okm)
 }
 
-/// assumes that one round is enough and 1 is appended to info
-pub fn hkdf_simple_expand<const N: usize>(prk: &[u8], info: &[u8]) -> Result<[u8; N]> {
-    to_array(hmac_sha256(prk, info)?)
+/// [hkdf_expand] into an array
+pub fn hkdf_expand_array<const N: usize>(prk: &[u8], info: &[u8]) -> Result<[u8; N]> {
+    to_array(hkdf_expand(prk, info, N)?)
 }
 
 pub fn gen_salt<const N: usize>() -> Result<[u8; N]> {
@@ -42,6 +71,204 @@ pub fn aes_gcm_decrypt(
     Ok(decr)
 }
 
+/// Size of the authentication tag of a record
+const TAG_SIZE: usize = 16;
+
+/// The header of the `aes128gcm` content coding (rfc8188 section 2.1)
+#[derive(Debug, PartialEq)]
+pub struct ContentHeader {
+    pub salt: [u8; 16],
+    pub record_size: u32,
+    pub key_id: Vec<u8>,
+}
+
+impl ContentHeader {
+    /// Parse the header from the beginning of the `content` and return it along with the rest
+    pub fn parse(content: &[u8]) -> Result<(Self, &[u8])> {
+        let (salt, rest) = content
+            .split_first_chunk::<16>()
+            .ok_or("truncated header")?;
+        let (record_size, rest) = rest.split_first_chunk::<4>().ok_or("truncated header")?;
+        let (id_len, rest) = rest.split_first().ok_or("truncated header")?;
+        if rest.len() < *id_len as usize {
+            return Err("truncated header".into());
+        }
+        let (key_id, rest) = rest.split_at(*id_len as usize);
+        let header = Self {
+            salt: *salt,
+            record_size: u32::from_be_bytes(*record_size),
+            key_id: key_id.to_vec(),
+        };
+        Ok((header, rest))
+    }
+}
+
+impl TryFrom<&ContentHeader> for Vec<u8> {
+    type Error = Error;
+
+    fn try_from(header: &ContentHeader) -> Result<Self> {
+        let id_len = u8::try_from(header.key_id.len()).map_err(|_| "too long key id")?;
+        Ok([
+            header.salt.as_slice(),
+            &header.record_size.to_be_bytes(),
+            &[id_len],
+            &header.key_id,
+        ]
+        .concat())
+    }
+}
+
+/// The state shared by [Aes128GcmEncoder] and [Aes128GcmDecoder]: the content encryption
+/// key and the nonce of the next record (rfc8188 section 2.2 - 2.3)
+struct RecordKeys {
+    cek: [u8; 16],
+    nonce: [u8; 12],
+    seq: u64,
+    record_size: usize,
+}
+
+impl RecordKeys {
+    fn new(ikm: &[u8], header: &ContentHeader) -> Result<Self> {
+        if header.record_size <= TAG_SIZE as u32 + 1 {
+            return Err(format!("invalid record size {}", header.record_size).into());
+        }
+        let prk = hkdf_extract(&header.salt, ikm)?;
+        Ok(Self {
+            cek: hkdf_expand_array(&prk, b"Content-Encoding: aes128gcm\0")?,
+            nonce: hkdf_expand_array(&prk, b"Content-Encoding: nonce\0")?,
+            seq: 0,
+            record_size: header.record_size as usize,
+        })
+    }
+
+    /// The nonce XORed with the sequence number of the record, which is then incremented
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = self.nonce;
+        for (n, s) in nonce[4..].iter_mut().zip(self.seq.to_be_bytes()) {
+            *n ^= s;
+        }
+        self.seq += 1;
+        nonce
+    }
+}
+
+/// Encrypts the content record by record with `aes128gcm` (rfc8188)
+pub struct Aes128GcmEncoder {
+    keys: RecordKeys,
+}
+
+impl Aes128GcmEncoder {
+    /// Encoder for the content with the given header, which is written by the caller
+    pub fn new(ikm: &[u8], header: &ContentHeader) -> Result<Self> {
+        Ok(Self {
+            keys: RecordKeys::new(ikm, header)?,
+        })
+    }
+
+    /// Maximum amount of data and padding in a single record
+    pub fn record_capacity(&self) -> usize {
+        self.keys.record_size - TAG_SIZE - 1
+    }
+
+    /// Encrypt the next record, followed by `padding` zeros. Only the last record may be
+    /// shorter than the record capacity.
+    pub fn encrypt_record(&mut self, data: &[u8], padding: usize, last: bool) -> Result<Vec<u8>> {
+        let len = data.len() + padding;
+        if len > self.record_capacity() || (!last && len < self.record_capacity()) {
+            return Err(format!("invalid record length {len}").into());
+        }
+        let delimiter = if last { 2 } else { 1 };
+        let plain = [data, &[delimiter], &vec![0; padding]].concat();
+        let nonce = self.keys.next_nonce();
+        let (encr, tag) = aes_gcm_encrypt(&plain, &self.keys.cek, &nonce)?;
+        Ok([encr, tag.to_vec()].concat())
+    }
+}
+
+/// Decrypts `aes128gcm` content (rfc8188) record by record
+pub struct Aes128GcmDecoder {
+    keys: RecordKeys,
+    done: bool,
+}
+
+impl Aes128GcmDecoder {
+    pub fn new(ikm: &[u8], header: &ContentHeader) -> Result<Self> {
+        Ok(Self {
+            keys: RecordKeys::new(ikm, header)?,
+            done: false,
+        })
+    }
+
+    /// Decrypt the next record and remove its padding. The last record must have the
+    /// delimiter 2 and the others 1 and the size of the record size.
+    pub fn decrypt_record(&mut self, record: &[u8], last: bool) -> Result<Vec<u8>> {
+        if self.done {
+            return Err("record after the last record".into());
+        }
+        if record.len() > self.keys.record_size || (!last && record.len() < self.keys.record_size) {
+            return Err(format!("invalid record length {}", record.len()).into());
+        }
+        let (encr, tag) = record
+            .split_last_chunk::<TAG_SIZE>()
+            .ok_or("truncated record")?;
+        let nonce = self.keys.next_nonce();
+        let mut plain = aes_gcm_decrypt(encr, &self.keys.cek, &nonce, tag)?;
+        let delimiter = plain
+            .iter()
+            .rposition(|b| *b != 0)
+            .ok_or("missing delimiter")?;
+        match (plain[delimiter], last) {
+            (1, false) | (2, true) => {}
+            (d, _) => return Err(format!("invalid delimiter {d}").into()),
+        }
+        plain.truncate(delimiter);
+        self.done = last;
+        Ok(plain)
+    }
+}
+
+/// Encode the `plain` as `aes128gcm` content with the header, splitting it into records of
+/// the header's record size. The `padding` fills the records after the data.
+pub fn aes128gcm_encode(
+    plain: &[u8],
+    padding: usize,
+    ikm: &[u8],
+    header: &ContentHeader,
+) -> Result<Vec<u8>> {
+    let mut encoder = Aes128GcmEncoder::new(ikm, header)?;
+    let capacity = encoder.record_capacity();
+    let mut content = Vec::try_from(header)?;
+    let (mut data, mut padding) = (plain, padding);
+    loop {
+        let (record, rest) = data.split_at(data.len().min(capacity));
+        let record_padding = padding.min(capacity - record.len());
+        let last = rest.is_empty() && record_padding == padding;
+        content.extend(encoder.encrypt_record(record, record_padding, last)?);
+        if last {
+            return Ok(content);
+        }
+        (data, padding) = (rest, padding - record_padding);
+    }
+}
+
+/// Decode `aes128gcm` content, with the input keying material derived from the header
+pub fn aes128gcm_decode(
+    content: &[u8],
+    ikm: impl FnOnce(&ContentHeader) -> Result<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    let (header, records) = ContentHeader::parse(content)?;
+    let mut decoder = Aes128GcmDecoder::new(&ikm(&header)?, &header)?;
+    let mut plain = Vec::new();
+    let mut records = records.chunks(header.record_size as usize).peekable();
+    while let Some(record) = records.next() {
+        plain.extend(decoder.decrypt_record(record, records.peek().is_none())?);
+    }
+    match decoder.done {
+        true => Ok(plain),
+        false => Err("missing the last record".into()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -64,20 +291,119 @@ mod tests {
         assert_eq!(data, decr.as_slice());
     }
 
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn hkdf_works() {
+        // from https://www.rfc-editor.org/rfc/rfc5869#appendix-A
+        let cases = [
+            (
+                vec![0x0b; 22],
+                hex("000102030405060708090a0b0c"),
+                hex("f0f1f2f3f4f5f6f7f8f9"),
+                "077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5",
+                "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865",
+            ),
+            (
+                (0x00..=0x4f).collect(),
+                (0x60..=0xaf).collect(),
+                (0xb0..=0xff).collect(),
+                "06a6b88c5853361a06104c9ceb35b45cef760014904671014a193f40c15fc244",
+                "b11e398dc80327a1c8e7f78c596a49344f012eda2d4efad8a050cc4c19afa97c59045a99cac7827271cb41c65e590e09da3275600c2f09b8367793a9aca3db71cc30c58179ec3e87c14c01d5c1f3434f1d87",
+            ),
+            (
+                vec![0x0b; 22],
+                vec![],
+                vec![],
+                "19ef24a32c717b167f33a91d6f648bdf96596776afdb6377ac434c1c293ccb04",
+                "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d9d201395faa4b61a96c8",
+            ),
+        ];
+        for (ikm, salt, info, prk_exp, okm_exp) in cases {
+            let prk = hkdf_extract(&salt, &ikm).unwrap();
+            assert_eq!(prk.to_vec(), hex(prk_exp));
+            let okm = hkdf_expand(&prk, &info, okm_exp.len() / 2).unwrap();
+            assert_eq!(okm, hex(okm_exp));
+        }
+        assert!(hkdf_expand(&[0; 32], b"", 255 * 32).is_ok());
+        assert!(hkdf_expand(&[0; 32], b"", 255 * 32 + 1).is_err());
+    }
+
     #[test]
-    fn hkdf_simple_expand_works() {
+    fn hkdf_expand_works_for_web_push() {
         // from https://www.rfc-editor.org/rfc/rfc8291#appendix-A
         let prk = vec_from_b64("09_eUZGrsvxChDCGRCdkLiDXrReGOEVeSCdCcPBSJSc");
 
-        let nonce = hkdf_simple_expand::<12>(&prk, b"Content-Encoding: nonce\0\x01").unwrap();
+        let nonce = hkdf_expand_array::<12>(&prk, b"Content-Encoding: nonce\0").unwrap();
         let nonce_exp = vec_from_b64("4h_95klXJ5E_qnoN");
         assert_eq!(&nonce, nonce_exp.as_slice());
 
-        let cek = hkdf_simple_expand::<16>(&prk, b"Content-Encoding: aes128gcm\0\x01").unwrap();
+        let cek = hkdf_expand_array::<16>(&prk, b"Content-Encoding: aes128gcm\0").unwrap();
         let cek_exp = vec_from_b64("oIhVW04MRdy2XN9CiKLxTg");
         assert_eq!(&cek, cek_exp.as_slice());
     }
 
+    #[test]
+    fn aes128gcm_encode_works() {
+        // from https://www.rfc-editor.org/rfc/rfc8188#section-3.1
+        let ikm = vec_from_b64("yqdlZ-tYemfogSmv7Ws5PQ");
+        let header = ContentHeader {
+            salt: arr_from_b64("I1BsxtFttlv3u_Oo94xnmw"),
+            record_size: 4096,
+            key_id: vec![],
+        };
+        let content = aes128gcm_encode(b"I am the walrus", 0, &ikm, &header).unwrap();
+        let content_exp = "I1BsxtFttlv3u_Oo94xnmwAAEAAA-NAVub2qFgBEuQKRapoZu-IxkIva3MEB1PD-ly8Thjg";
+        assert_eq!(base64url_encode(&content), content_exp);
+        let plain = aes128gcm_decode(&content, |_| Ok(ikm.clone())).unwrap();
+        assert_eq!(plain, b"I am the walrus");
+    }
+
+    #[test]
+    fn aes128gcm_decode_works_with_multiple_records() {
+        // from https://www.rfc-editor.org/rfc/rfc8188#section-3.2
+        let ikm = vec_from_b64("BO3ZVPxUlnLORbVGMpbT1Q");
+        let content = vec_from_b64("uNCkWiNYzKTnBN9ji3-qWAAAABkCYTHOG8chz_gnvgOqdGYovxyjuqRyJFjEDyoF1Fvkj6hQPdPHI51OEUKEpgz3SsLWIqS_uA");
+        let plain = aes128gcm_decode(&content, |header| {
+            assert_eq!(header.key_id, b"a1");
+            assert_eq!(header.record_size, 25);
+            Ok(ikm.clone())
+        })
+        .unwrap();
+        assert_eq!(plain, b"I am the walrus");
+
+        assert!(aes128gcm_decode(&content[..content.len() - 25], |_| Ok(ikm.clone())).is_err());
+        assert!(aes128gcm_decode(&content, |_| Ok(vec![0; 16])).is_err());
+    }
+
+    #[test]
+    fn aes128gcm_records_are_split_and_padded() {
+        let ikm = [1; 16];
+        let header = ContentHeader {
+            salt: [2; 16],
+            record_size: 25,
+            key_id: b"key".to_vec(),
+        };
+        let decode = |content: &[u8]| aes128gcm_decode(content, |_| Ok(ikm.to_vec())).unwrap();
+        for (plain, padding, records) in [
+            (&b""[..], 0, 1),
+            (b"I am the", 0, 1),
+            (b"I am the walrus", 0, 2),
+            (b"I am the walrus", 10, 4),
+            (b"", 20, 3),
+        ] {
+            let content = aes128gcm_encode(plain, padding, &ikm, &header).unwrap();
+            let body_len = content.len() - 24;
+            assert_eq!(body_len.div_ceil(25), records);
+            assert_eq!(decode(&content), plain);
+        }
+    }
+
     #[test]
     fn encryption_works() {
         // from https://www.rfc-editor.org/rfc/rfc8291#appendix-A
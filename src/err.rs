@@ -9,9 +9,15 @@ pub enum Error {
     DeadpoolSqlite(deadpool_sqlite::rusqlite::Error),
     Header(reqwest::header::InvalidHeaderValue),
     Io(io::Error),
+    /// The encrypted push message would exceed the limit of the push services
+    PayloadTooLarge {
+        size: usize,
+        max: usize,
+    },
     Reqwest(reqwest::Error),
     SerdeJson(serde_json::Error),
     OpenSSL(openssl::error::ErrorStack),
+    Vapid(crate::jwt::VapidError),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -26,9 +32,13 @@ impl fmt::Display for Error {
             Error::DeadpoolSqlite(e) => write!(f, "{e}"),
             Error::Header(e) => write!(f, "{e}"),
             Error::Io(e) => write!(f, "{e}"),
+            Error::PayloadTooLarge { size, max } => {
+                write!(f, "payload too large: {size} bytes, at most {max} allowed")
+            }
             Error::Reqwest(e) => write!(f, "{e}"),
             Error::SerdeJson(e) => write!(f, "{e}"),
             Error::OpenSSL(e) => write!(f, "{e}"),
+            Error::Vapid(e) => write!(f, "{e}"),
         }
     }
 }
@@ -124,6 +134,12 @@ impl From<openssl::error::ErrorStack> for Error {
     }
 }
 
+impl From<crate::jwt::VapidError> for Error {
+    fn from(value: crate::jwt::VapidError) -> Self {
+        Self::Vapid(value)
+    }
+}
+
 impl From<String> for Error {
     fn from(value: String) -> Self {
         Self::Io(io::Error::other(value))
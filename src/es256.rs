@@ -1,5 +1,8 @@
 use crate::base64::base64url_decode;
-use crate::encr::{aes_gcm_encrypt, hkdf_simple_expand, hmac_sha256};
+use crate::encr::{
+    aes128gcm_decode, aes128gcm_encode, aes_gcm_decrypt, aes_gcm_encrypt, hkdf_expand,
+    hkdf_expand_array, hkdf_extract, ContentHeader,
+};
 use crate::err::{Error, Result};
 use openssl::bn::{BigNum, BigNumContext};
 use openssl::derive::Deriver;
@@ -9,6 +12,71 @@ use openssl::nid::Nid;
 use openssl::pkey::{PKey, Private, Public};
 use openssl::sha::sha256;
 
+/// Record size written into the header, the content is always a single record
+pub const RECORD_SIZE: usize = 4096;
+/// Maximum size of the encrypted body that push services must accept (rfc8030 section 7.2)
+pub const MAX_BODY_SIZE: usize = 4096;
+/// Salt, record size, key id length and the uncompressed public key (rfc8291 section 4)
+const HEADER_SIZE: usize = 16 + 4 + 1 + 65;
+/// Padding delimiter and the authentication tag
+const RECORD_OVERHEAD: usize = 1 + 16;
+/// Maximum size of the plaintext that fits into [MAX_BODY_SIZE] and a single record
+pub const MAX_PLAINTEXT_SIZE: usize = MAX_BODY_SIZE - HEADER_SIZE - RECORD_OVERHEAD;
+
+/// Check that the plaintext can be encrypted with [Es256::mk_content]
+pub fn check_plaintext_size(size: usize) -> Result<()> {
+    match size <= MAX_PLAINTEXT_SIZE {
+        true => Ok(()),
+        false => Err(Error::PayloadTooLarge {
+            size,
+            max: MAX_PLAINTEXT_SIZE,
+        }),
+    }
+}
+
+/// rfc8291 section 3.4
+fn derive_ikm(ecdh_secret: &[u8], key_info: &[u8], auth_secret: &[u8; 16]) -> Result<Vec<u8>> {
+    let prk_key = hkdf_extract(auth_secret, ecdh_secret)?;
+    hkdf_expand(&prk_key, key_info, 32)
+}
+
+/// Content encryption key and nonce of the legacy `aesgcm` encoding
+/// (draft-ietf-webpush-encryption-04 section 3.3, draft-ietf-httpbis-encryption-encoding-03)
+fn aesgcm_keys(
+    ecdh_secret: &[u8],
+    context: &[u8],
+    auth_secret: &[u8; 16],
+    salt: &[u8; 16],
+) -> Result<([u8; 16], [u8; 12])> {
+    let prk_key = hkdf_extract(auth_secret, ecdh_secret)?;
+    let ikm = hkdf_expand(&prk_key, b"Content-Encoding: auth\0", 32)?;
+    aesgcm_record_keys(&ikm, context, salt)
+}
+
+/// Content encryption key and nonce derived from the input keying material
+/// (draft-ietf-httpbis-encryption-encoding-03 section 2.2 and 2.3)
+fn aesgcm_record_keys(ikm: &[u8], context: &[u8], salt: &[u8; 16]) -> Result<([u8; 16], [u8; 12])> {
+    let prk = hkdf_extract(salt, ikm)?;
+    let cek_info = [b"Content-Encoding: aesgcm\0", context].concat();
+    let nonce_info = [b"Content-Encoding: nonce\0", context].concat();
+    Ok((
+        hkdf_expand_array(&prk, &cek_info)?,
+        hkdf_expand_array(&prk, &nonce_info)?,
+    ))
+}
+
+/// Decrypt a record of the `aesgcm` encoding and strip its padding
+fn decrypt_record_aesgcm(content: &[u8], cek: &[u8; 16], nonce: &[u8; 12]) -> Result<Vec<u8>> {
+    let (encr, tag) = content.split_last_chunk::<16>().ok_or("truncated record")?;
+    let record = aes_gcm_decrypt(encr, cek, nonce, tag)?;
+    let (padding, rest) = record.split_first_chunk::<2>().ok_or("truncated record")?;
+    let padding = u16::from_be_bytes(*padding) as usize;
+    match rest.get(..padding) {
+        Some(zeros) if zeros.iter().all(|b| *b == 0) => Ok(rest[padding..].to_vec()),
+        _ => Err("invalid padding".into()),
+    }
+}
+
 fn get_grp() -> Result<EcGroup> {
     Ok(EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?)
 }
@@ -26,18 +94,40 @@ impl Es256Pub {
         Ok([b"WebPush: info\0", ua_bytes.as_slice(), as_bytes.as_slice()].concat())
     }
 
-    /// rfc8188 section 2.1
-    pub fn mk_header(&self, salt: &[u8]) -> Result<Vec<u8>> {
+    /// The context of the legacy `aesgcm` encoding: both public keys prefixed with their length
+    fn aesgcm_context(&self, user_pub_key: &Self) -> Result<Vec<u8>> {
+        let ua_bytes = Vec::try_from(user_pub_key)?;
         let as_bytes = Vec::try_from(self)?;
-        let as_pub_len = as_bytes.len() as u8;
         Ok([
-            salt,
-            &4096u32.to_be_bytes(),
-            &[as_pub_len],
-            as_bytes.as_slice(),
+            b"P-256\0".as_slice(),
+            &(ua_bytes.len() as u16).to_be_bytes(),
+            &ua_bytes,
+            &(as_bytes.len() as u16).to_be_bytes(),
+            &as_bytes,
         ]
         .concat())
     }
+
+    /// Verify the signature signed with [Es256::sign], i.e. the `r` and `s` of the
+    /// signature as 32-byte integers
+    pub fn verify(&self, data: &[u8], sig: &[u8]) -> Result<bool> {
+        if sig.len() != 64 {
+            return Ok(false);
+        }
+        let r = BigNum::from_slice(&sig[..32])?;
+        let s = BigNum::from_slice(&sig[32..])?;
+        let sig = EcdsaSig::from_private_components(r, s)?;
+        Ok(sig.verify(&sha256(data), &self.key)?)
+    }
+
+    /// rfc8188 section 2.1, with the public key as the key id (rfc8291 section 4)
+    pub fn mk_header(&self, salt: &[u8; 16]) -> Result<ContentHeader> {
+        Ok(ContentHeader {
+            salt: *salt,
+            record_size: RECORD_SIZE as u32,
+            key_id: Vec::try_from(self)?,
+        })
+    }
 }
 
 impl TryFrom<&[u8]> for Es256Pub {
@@ -85,50 +175,90 @@ impl Es256 {
         Ok(deriver.derive_to_vec()?)
     }
 
-    /// rfc8188 section 2.2
-    fn mk_prk(
-        &self,
-        peer_pubkey: &Es256Pub,
-        auth_secret: &[u8; 16],
-        salt: &[u8; 16],
-    ) -> Result<Vec<u8>> {
+    /// rfc8291 section 3.4
+    fn mk_ikm(&self, peer_pubkey: &Es256Pub, auth_secret: &[u8; 16]) -> Result<Vec<u8>> {
         let self_pub = Es256Pub::try_from(self)?;
         let key_info = self_pub.key_info(peer_pubkey)?;
         let ecdh_secret = self.derive_ecdh_secret(peer_pubkey)?;
-        let prk_key = hmac_sha256(auth_secret, &ecdh_secret)?;
-        let ikm = hmac_sha256(&prk_key, &[key_info.as_slice(), &[1]].concat())?;
-        hmac_sha256(salt, &ikm)
+        derive_ikm(&ecdh_secret, &key_info, auth_secret)
     }
 
-    /// rfc8188 with `Content-Encoding: nonce` (2.3, 3.1)
+    /// rfc8291 section 4: a single record of `aes128gcm` content coding with `padding`
+    /// zeros after the delimiter
     pub fn mk_content(
         &self,
         peer_pubkey: &Es256Pub,
         auth_secret: &[u8; 16],
         salt: &[u8; 16],
         plain: &[u8],
+        padding: usize,
     ) -> Result<Vec<u8>> {
+        check_plaintext_size(plain.len() + padding)?;
         let header = Es256Pub::try_from(self)?.mk_header(salt)?;
-        let prk = self.mk_prk(peer_pubkey, auth_secret, salt)?;
-        let nonce = hkdf_simple_expand(&prk, b"Content-Encoding: nonce\0\x01")?;
-        let cek = hkdf_simple_expand(&prk, b"Content-Encoding: aes128gcm\0\x01")?;
-        let (encr, tag) = aes_gcm_encrypt(&[plain, &[2]].concat(), &cek, &nonce)?;
-        let encr = [encr, tag.to_vec()].concat();
-        Ok([header, encr].concat())
+        let ikm = self.mk_ikm(peer_pubkey, auth_secret)?;
+        aes128gcm_encode(plain, padding, &ikm, &header)
+    }
+
+    /// Inverse of [Es256::mk_content], with `self` as the key of the user agent
+    /// (rfc8291 section 3)
+    pub fn decrypt_content(&self, auth_secret: &[u8; 16], content: &[u8]) -> Result<Vec<u8>> {
+        aes128gcm_decode(content, |header| {
+            let as_pub = Es256Pub::try_from(header.key_id.as_slice())?;
+            let key_info = as_pub.key_info(&Es256Pub::try_from(self)?)?;
+            let ecdh_secret = self.derive_ecdh_secret(&as_pub)?;
+            derive_ikm(&ecdh_secret, &key_info, auth_secret)
+        })
+    }
+
+    /// The legacy `aesgcm` encoding: a single record starting with the length of the padding.
+    /// Unlike with [Es256::mk_content], the salt and the public key are not included in the
+    /// content, but sent in the `Encryption` and `Crypto-Key` headers.
+    pub fn mk_content_aesgcm(
+        &self,
+        peer_pubkey: &Es256Pub,
+        auth_secret: &[u8; 16],
+        salt: &[u8; 16],
+        plain: &[u8],
+        padding: usize,
+    ) -> Result<Vec<u8>> {
+        check_plaintext_size(plain.len() + padding)?;
+        let context = Es256Pub::try_from(self)?.aesgcm_context(peer_pubkey)?;
+        let ecdh_secret = self.derive_ecdh_secret(peer_pubkey)?;
+        let (cek, nonce) = aesgcm_keys(&ecdh_secret, &context, auth_secret, salt)?;
+        let record = [
+            &(padding as u16).to_be_bytes()[..],
+            &vec![0; padding],
+            plain,
+        ]
+        .concat();
+        let (encr, tag) = aes_gcm_encrypt(&record, &cek, &nonce)?;
+        Ok([encr, tag.to_vec()].concat())
+    }
+
+    /// Inverse of [Es256::mk_content_aesgcm], with `self` as the key of the user agent
+    pub fn decrypt_content_aesgcm(
+        &self,
+        as_pubkey: &Es256Pub,
+        auth_secret: &[u8; 16],
+        salt: &[u8; 16],
+        content: &[u8],
+    ) -> Result<Vec<u8>> {
+        let context = as_pubkey.aesgcm_context(&Es256Pub::try_from(self)?)?;
+        let ecdh_secret = self.derive_ecdh_secret(as_pubkey)?;
+        let (cek, nonce) = aesgcm_keys(&ecdh_secret, &context, auth_secret, salt)?;
+        decrypt_record_aesgcm(content, &cek, &nonce)
     }
 
-    /// Sign the `data` with ecdsa
+    /// Sign the `data` with ecdsa, the signature is `r` and `s` as 32-byte integers
+    /// (rfc7518 section 3.4)
     pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
         let sig = EcdsaSig::sign(&sha256(data), &self.key)?;
-        Ok([sig.r().to_vec(), sig.s().to_vec()].concat())
+        Ok([sig.r().to_vec_padded(32)?, sig.s().to_vec_padded(32)?].concat())
     }
 
     /// Verify the signature signed with [Es256::sign]
     pub fn verify(&self, data: &[u8], sig: &[u8]) -> Result<bool> {
-        let r = BigNum::from_slice(&sig[..32])?;
-        let s = BigNum::from_slice(&sig[32..64])?;
-        let sig = EcdsaSig::from_private_components(r, s)?;
-        Ok(sig.verify(&sha256(data), &self.key)?)
+        Es256Pub::try_from(self)?.verify(data, sig)
     }
 
     pub fn private_key(&self) -> Vec<u8> {
@@ -208,10 +338,10 @@ mod tests {
     #[test]
     fn mk_header_works() {
         let as_public = es_pub_from_b64("BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8");
-        let salt = vec_from_b64("DGv6ra1nlYgDCS1FRnbzlw");
+        let salt = arr_from_b64("DGv6ra1nlYgDCS1FRnbzlw");
         let header_exp = vec_from_b64("DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8");
         let header = as_public.mk_header(&salt).unwrap();
-        assert_eq!(header, header_exp);
+        assert_eq!(Vec::try_from(&header).unwrap(), header_exp);
     }
 
     #[test]
@@ -229,19 +359,22 @@ mod tests {
     }
 
     #[test]
-    fn prk_works() {
+    fn ikm_works() {
         // from https://www.rfc-editor.org/rfc/rfc8291#appendix-A
         let as_es = es_from_b64(
             "yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw",
             "BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8"
         );
         let ua_public = es_pub_from_b64("BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4");
-        let salt = arr_from_b64("DGv6ra1nlYgDCS1FRnbzlw");
+        let salt = arr_from_b64::<16>("DGv6ra1nlYgDCS1FRnbzlw");
         let auth_secret = arr_from_b64("BTBZMqHH6r4Tts7J_aSIgg");
 
+        let ikm_exp = vec_from_b64("S4lYMb_L0FxCeq0WhDx813KgSYqU26kOyzWUdsXYyrg");
+        let ikm = as_es.mk_ikm(&ua_public, &auth_secret).unwrap();
+        assert_eq!(ikm, ikm_exp);
+
         let prk_exp = vec_from_b64("09_eUZGrsvxChDCGRCdkLiDXrReGOEVeSCdCcPBSJSc");
-        let prk = as_es.mk_prk(&ua_public, &auth_secret, &salt).unwrap();
-        assert_eq!(prk, prk_exp);
+        assert_eq!(hkdf_extract(&salt, &ikm).unwrap().to_vec(), prk_exp);
     }
 
     #[test]
@@ -255,12 +388,123 @@ mod tests {
         let auth = arr_from_b64("BTBZMqHH6r4Tts7J_aSIgg");
         let plain = vec_from_b64("V2hlbiBJIGdyb3cgdXAsIEkgd2FudCB0byBiZSBhIHdhdGVybWVsb24");
 
-        let content = as_es.mk_content(&ua_public, &auth, &salt, &plain).unwrap();
+        let content = as_es
+            .mk_content(&ua_public, &auth, &salt, &plain, 0)
+            .unwrap();
         let content_exp = vec_from_b64("DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN");
         assert_eq!(content.len(), 144);
         assert_eq!(content, content_exp);
     }
 
+    #[test]
+    fn decrypt_content_works() {
+        // from https://www.rfc-editor.org/rfc/rfc8291#appendix-A
+        let ua_es = es_from_b64(
+            "q1dXpw3UpT5VOmu_cf_v6ih07Aems3njxI-JWgLcM94",
+            "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4"
+        );
+        let auth = arr_from_b64("BTBZMqHH6r4Tts7J_aSIgg");
+        let content = vec_from_b64("DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN");
+
+        let (header, _) = ContentHeader::parse(&content).unwrap();
+        assert_eq!(header.record_size, 4096);
+        assert_eq!(header.key_id, vec_from_b64("BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8"));
+
+        let plain = ua_es.decrypt_content(&auth, &content).unwrap();
+        assert_eq!(plain, b"When I grow up, I want to be a watermelon");
+
+        let mut tampered = content.clone();
+        tampered[100] ^= 1;
+        assert!(ua_es.decrypt_content(&auth, &tampered).is_err());
+        assert!(ua_es.decrypt_content(&auth, &content[..50]).is_err());
+        assert!(ua_es.decrypt_content(&[0; 16], &content).is_err());
+    }
+
+    #[test]
+    fn padding_is_stripped() {
+        let as_es = Es256::gen().unwrap();
+        let ua_es = Es256::gen().unwrap();
+        let ua_public = Es256Pub::try_from(&ua_es).unwrap();
+        let auth = [7; 16];
+        let salt = [3; 16];
+        for padding in [0, 1, 100, MAX_PLAINTEXT_SIZE - 5] {
+            let content = as_es
+                .mk_content(&ua_public, &auth, &salt, b"plain", padding)
+                .unwrap();
+            assert_eq!(ua_es.decrypt_content(&auth, &content).unwrap(), b"plain");
+        }
+    }
+
+    #[test]
+    fn aesgcm_content_can_be_decrypted() {
+        let as_es = es_from_b64(
+            "yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw",
+            "BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8"
+        );
+        let ua_es = es_from_b64(
+            "q1dXpw3UpT5VOmu_cf_v6ih07Aems3njxI-JWgLcM94",
+            "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4"
+        );
+        let as_public = Es256Pub::try_from(&as_es).unwrap();
+        let ua_public = Es256Pub::try_from(&ua_es).unwrap();
+        let salt = arr_from_b64("DGv6ra1nlYgDCS1FRnbzlw");
+        let auth = arr_from_b64("BTBZMqHH6r4Tts7J_aSIgg");
+        let plain = b"When I grow up, I want to be a watermelon";
+
+        for padding in [0, 1, 100] {
+            let content = as_es
+                .mk_content_aesgcm(&ua_public, &auth, &salt, plain, padding)
+                .unwrap();
+            assert_eq!(content.len(), 2 + padding + plain.len() + 16);
+            let decr = ua_es.decrypt_content_aesgcm(&as_public, &auth, &salt, &content);
+            assert_eq!(decr.unwrap(), plain);
+        }
+        let content = as_es
+            .mk_content_aesgcm(&ua_public, &auth, &salt, plain, 0)
+            .unwrap();
+        assert!(ua_es
+            .decrypt_content_aesgcm(&as_public, &[0; 16], &salt, &content)
+            .is_err());
+    }
+
+    #[test]
+    fn aesgcm_record_is_decrypted() {
+        // from https://datatracker.ietf.org/doc/html/draft-ietf-httpbis-encryption-encoding-03#section-3.1
+        let ikm = vec_from_b64("csPJEXBYA5U-Tal9EdJi-w");
+        let salt = arr_from_b64("vr0o6Uq3w_KDWeatc27mUg");
+        let content = vec_from_b64("VDeU0XxaJkOJDAxPl7h9JD5V8N43RorP7PfpPdZZQuwF");
+        let (cek, nonce) = aesgcm_record_keys(&ikm, &[], &salt).unwrap();
+        let plain = decrypt_record_aesgcm(&content, &cek, &nonce).unwrap();
+        assert_eq!(plain, b"I am the walrus");
+        assert!(decrypt_record_aesgcm(&content, &cek, &[0; 12]).is_err());
+    }
+
+    #[test]
+    fn mk_content_checks_size() {
+        let as_es = Es256::gen().unwrap();
+        let ua_public = es_pub_from_b64("BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4");
+        let salt = arr_from_b64("DGv6ra1nlYgDCS1FRnbzlw");
+        let auth = arr_from_b64("BTBZMqHH6r4Tts7J_aSIgg");
+
+        let plain = vec![b'a'; MAX_PLAINTEXT_SIZE];
+        let content = as_es
+            .mk_content(&ua_public, &auth, &salt, &plain, 0)
+            .unwrap();
+        assert_eq!(content.len(), MAX_BODY_SIZE);
+
+        let content = as_es.mk_content(&ua_public, &auth, &salt, b"a", MAX_PLAINTEXT_SIZE - 1);
+        assert_eq!(content.unwrap().len(), MAX_BODY_SIZE);
+
+        let plain = vec![b'a'; MAX_PLAINTEXT_SIZE + 1];
+        match as_es.mk_content(&ua_public, &auth, &salt, &plain, 0) {
+            Err(Error::PayloadTooLarge { size, max }) => {
+                assert_eq!(size, MAX_PLAINTEXT_SIZE + 1);
+                assert_eq!(max, MAX_PLAINTEXT_SIZE);
+            }
+            res => panic!("unexpected result {res:?}"),
+        }
+    }
+
     #[test]
     fn signatures_are_verified() {
         let key = Es256::gen().unwrap();
@@ -271,5 +515,13 @@ mod tests {
 
         sig[0] ^= 17;
         assert!(!key.verify(data, &sig).unwrap());
+        assert!(!key.verify(data, &sig[..63]).unwrap());
+
+        let key_pub = Es256Pub::try_from(&key).unwrap();
+        for _ in 0..300 {
+            let sig = key.sign(data).unwrap();
+            assert_eq!(sig.len(), 64);
+            assert!(key_pub.verify(data, &sig).unwrap());
+        }
     }
 }
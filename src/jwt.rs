@@ -1,7 +1,13 @@
+use crate::base64::{base64url_decode, base64url_encode};
 use crate::err::Result;
-use crate::es256::Es256;
-use crate::{base64::base64url_encode, err_other};
+use crate::err_other;
+use crate::es256::{Es256, Es256Pub};
+use crate::utils::parse_var_or;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 use url::Url;
 
@@ -20,30 +26,156 @@ impl JwtHeader {
     }
 }
 
+/// The claims of a VAPID JWT (rfc8292 section 2)
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
-struct JwtPayload {
-    aud: String,
-    exp: u32,
-    sub: String,
+pub struct Claims {
+    /// The origin of the push resource
+    pub aud: String,
+    /// Expiration time in seconds since the unix epoch
+    pub exp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<u64>,
+    /// Contact information of the application server, a `mailto:` or `https:` URI
+    pub sub: String,
 }
 
-fn mk_jwt_data(
-    push_resource: &Url,
-    sub: &Url,
-    ttl_minutes: u32,
-) -> Result<(JwtHeader, JwtPayload)> {
-    let header = JwtHeader::es_256();
+/// Reasons for rejecting a VAPID authorization in [verify_vapid]
+#[derive(Debug, PartialEq)]
+pub enum VapidError {
+    /// Not of the form `vapid t=<jwt>, k=<key>`
+    InvalidHeader,
+    /// The JWT could not be decoded
+    InvalidToken,
+    /// `k` is not a P-256 public key
+    InvalidKey,
+    UnsupportedAlgorithm(String),
+    InvalidSignature,
+    AudienceMismatch {
+        expected: String,
+        actual: String,
+    },
+    Expired {
+        exp: u64,
+    },
+    /// `exp` is further than [MAX_TTL] in the future
+    LifetimeTooLong {
+        exp: u64,
+    },
+    NotYetValid {
+        nbf: u64,
+    },
+    InvalidSubject(String),
+}
+
+impl fmt::Display for VapidError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VapidError::InvalidHeader => write!(f, "invalid vapid authorization header"),
+            VapidError::InvalidToken => write!(f, "invalid jwt"),
+            VapidError::InvalidKey => write!(f, "invalid vapid public key"),
+            VapidError::UnsupportedAlgorithm(alg) => write!(f, "unsupported jwt algorithm {alg}"),
+            VapidError::InvalidSignature => write!(f, "invalid jwt signature"),
+            VapidError::AudienceMismatch { expected, actual } => {
+                write!(f, "jwt audience {actual} does not match {expected}")
+            }
+            VapidError::Expired { exp } => write!(f, "jwt expired at {exp}"),
+            VapidError::LifetimeTooLong { exp } => {
+                write!(
+                    f,
+                    "jwt expires at {exp}, more than {MAX_TTL} s in the future"
+                )
+            }
+            VapidError::NotYetValid { nbf } => write!(f, "jwt not valid before {nbf}"),
+            VapidError::InvalidSubject(sub) => write!(f, "invalid jwt subject {sub}"),
+        }
+    }
+}
+
+impl std::error::Error for VapidError {}
+
+/// Maximum lifetime of a VAPID JWT in seconds (rfc8292 section 2)
+pub const MAX_TTL: u64 = 24 * 60 * 60;
+
+/// The lifetime and the time claims of the JWTs
+#[derive(Clone, Debug, PartialEq)]
+pub struct JwtOptions {
+    /// Seconds until `exp`
+    ttl: u64,
+    /// Seconds that the clock of the push service may be behind ours. `iat` and `nbf` are
+    /// back-dated by `skew`, and `ttl + skew` is capped at [MAX_TTL] so that `exp` is within
+    /// [MAX_TTL] of the back-dated `iat`.
+    skew: u64,
+    /// Include `iat` and `nbf` claims
+    issued_at: bool,
+}
+
+impl Default for JwtOptions {
+    fn default() -> Self {
+        Self {
+            ttl: 10 * 60,
+            skew: 0,
+            issued_at: false,
+        }
+    }
+}
+
+impl JwtOptions {
+    pub fn new(ttl: u64, skew: u64, issued_at: bool) -> Result<Self> {
+        if ttl == 0 || ttl + skew > MAX_TTL {
+            return Err(format!(
+                "invalid jwt lifetime {ttl} s with skew {skew} s, at most {MAX_TTL} s in total allowed"
+            )
+            .into());
+        }
+        Ok(Self {
+            ttl,
+            skew,
+            issued_at,
+        })
+    }
+
+    pub fn from_env() -> Result<Self> {
+        let default = Self::default();
+        Self::new(
+            parse_var_or("VAPID_JWT_TTL_SECONDS", default.ttl)?,
+            parse_var_or("VAPID_JWT_SKEW_SECONDS", default.skew)?,
+            parse_var_or("VAPID_JWT_ISSUED_AT", default.issued_at)?,
+        )
+    }
+
+    pub fn ttl(&self) -> u64 {
+        self.ttl
+    }
+}
+
+fn now() -> Result<u64> {
     let time = err_other!(SystemTime::now().duration_since(UNIX_EPOCH))?;
-    let payload = JwtPayload {
-        aud: push_resource.origin().ascii_serialization(),
-        exp: time.as_secs() as u32 + ttl_minutes * 60,
+    Ok(time.as_secs())
+}
+
+/// The audience of the JWT: the origin of the push resource
+fn audience(push_resource: &Url) -> String {
+    push_resource.origin().ascii_serialization()
+}
+
+/// Header and claims of a JWT issued at `now`
+fn mk_jwt_data(push_resource: &Url, sub: &Url, opts: &JwtOptions, now: u64) -> (JwtHeader, Claims) {
+    let header = JwtHeader::es_256();
+    let issued_at = opts.issued_at.then(|| now.saturating_sub(opts.skew));
+    let payload = Claims {
+        aud: audience(push_resource),
+        exp: now + opts.ttl,
+        iat: issued_at,
+        nbf: issued_at,
         sub: sub.to_string(),
     };
 
-    Ok((header, payload))
+    (header, payload)
 }
 
-fn to_signed_jwt(info: &JwtHeader, payload: &JwtPayload, key: &Es256) -> Result<String> {
+fn to_signed_jwt(info: &JwtHeader, payload: &Claims, key: &Es256) -> Result<String> {
     let header = serde_json::to_string(info).map(|s| base64url_encode(s.as_bytes()))?;
     let payload = serde_json::to_string(payload).map(|p| base64url_encode(p.as_bytes()))?;
     let data = [header, payload].join(".");
@@ -52,8 +184,13 @@ fn to_signed_jwt(info: &JwtHeader, payload: &JwtPayload, key: &Es256) -> Result<
 }
 
 /// Returns a JWT signed with `key` as a string.
-pub fn mk_jwt(push_resource: &Url, subject: &Url, ttl_minutes: u32, key: &Es256) -> Result<String> {
-    let (header, payload) = mk_jwt_data(push_resource, subject, ttl_minutes)?;
+pub fn mk_jwt(
+    push_resource: &Url,
+    subject: &Url,
+    opts: &JwtOptions,
+    key: &Es256,
+) -> Result<String> {
+    let (header, payload) = mk_jwt_data(push_resource, subject, opts, now()?);
     to_signed_jwt(&header, &payload, key)
 }
 
@@ -61,19 +198,138 @@ pub fn mk_jwt(push_resource: &Url, subject: &Url, ttl_minutes: u32, key: &Es256)
 pub fn mk_vapid_jwt(
     push_resource: &Url,
     subject: &Url,
-    ttl_minutes: u32,
+    opts: &JwtOptions,
     key: &Es256,
 ) -> Result<(String, String)> {
-    let jwt = mk_jwt(push_resource, subject, ttl_minutes, key)?;
+    let jwt = mk_jwt(push_resource, subject, opts, key)?;
     let k = base64url_encode(key.public_key()?);
     Ok((jwt, k))
 }
 
+/// Verify the `Authorization` header of a push message (rfc8292 section 3): the signature
+/// of the JWT with the key `k` and that the claims are valid for the push service
+/// at `expected_aud`.
+pub fn verify_vapid(
+    authorization: &str,
+    expected_aud: &str,
+) -> std::result::Result<Claims, VapidError> {
+    // a clock before the epoch fails the lifetime check
+    verify_vapid_at(authorization, expected_aud, now().unwrap_or_default())
+}
+
+fn verify_vapid_at(
+    authorization: &str,
+    expected_aud: &str,
+    now: u64,
+) -> std::result::Result<Claims, VapidError> {
+    // auth schemes are case-insensitive (rfc9110 section 11.1)
+    let params = match authorization.split_once(' ') {
+        Some((scheme, params)) if scheme.eq_ignore_ascii_case("vapid") => params,
+        _ => return Err(VapidError::InvalidHeader),
+    };
+    let (mut t, mut k) = (None, None);
+    for param in params.split(',') {
+        match param.trim().split_once('=') {
+            Some(("t", v)) => t = Some(v),
+            Some(("k", v)) => k = Some(v),
+            _ => return Err(VapidError::InvalidHeader),
+        }
+    }
+    let (Some(t), Some(k)) = (t, k) else {
+        return Err(VapidError::InvalidHeader);
+    };
+
+    let (data, sig) = t.rsplit_once('.').ok_or(VapidError::InvalidToken)?;
+    let (header, claims) = data.split_once('.').ok_or(VapidError::InvalidToken)?;
+    let header: JwtHeader = decode_json(header)?;
+    if header.alg != "ES256" {
+        return Err(VapidError::UnsupportedAlgorithm(header.alg));
+    }
+    let key = base64url_decode(k)
+        .and_then(|k| Es256Pub::try_from(k.as_slice()))
+        .map_err(|_| VapidError::InvalidKey)?;
+    let sig = base64url_decode(sig).map_err(|_| VapidError::InvalidSignature)?;
+    if !key.verify(data.as_bytes(), &sig).unwrap_or(false) {
+        return Err(VapidError::InvalidSignature);
+    }
+
+    let claims: Claims = decode_json(claims)?;
+    if claims.aud != expected_aud {
+        return Err(VapidError::AudienceMismatch {
+            expected: expected_aud.to_string(),
+            actual: claims.aud,
+        });
+    }
+    if claims.exp <= now {
+        return Err(VapidError::Expired { exp: claims.exp });
+    }
+    if claims.exp > now + MAX_TTL {
+        return Err(VapidError::LifetimeTooLong { exp: claims.exp });
+    }
+    if let Some(nbf) = claims.nbf.filter(|nbf| *nbf > now) {
+        return Err(VapidError::NotYetValid { nbf });
+    }
+    if !is_valid_subject(&claims.sub) {
+        return Err(VapidError::InvalidSubject(claims.sub));
+    }
+    Ok(claims)
+}
+
+fn decode_json<T: DeserializeOwned>(encoded: &str) -> std::result::Result<T, VapidError> {
+    let decoded = base64url_decode(encoded).map_err(|_| VapidError::InvalidToken)?;
+    serde_json::from_slice(&decoded).map_err(|_| VapidError::InvalidToken)
+}
+
+/// The subject is a `mailto:` or a `https:` URI (rfc8292 section 2.1)
+fn is_valid_subject(sub: &str) -> bool {
+    match Url::parse(sub) {
+        Ok(url) if url.scheme() == "mailto" => url.path().contains('@'),
+        Ok(url) if url.scheme() == "https" => url.has_host(),
+        _ => false,
+    }
+}
+
+/// Signed VAPID JWTs by audience. As the JWT depends only on the audience, the subject and
+/// the key, it is reused for all the subscriptions of a push service until it is about to
+/// expire, the last tenth of the lifetime of the token, so that a delivery with it does not
+/// outlive it.
+#[derive(Debug, Default)]
+pub struct VapidJwtCache {
+    tokens: Mutex<HashMap<String, (String, u64)>>,
+}
+
+impl VapidJwtCache {
+    /// Return a cached JWT for the push resource or create a new one with
+    /// [mk_jwt] if there is none or it is about to expire.
+    pub fn get(
+        &self,
+        push_resource: &Url,
+        subject: &Url,
+        opts: &JwtOptions,
+        key: &Es256,
+    ) -> Result<String> {
+        let aud = audience(push_resource);
+        let refresh_margin = opts.ttl / 10;
+        let now = now()?;
+        if let Some((jwt, exp)) = self.lock()?.get(&aud) {
+            if now + refresh_margin < *exp {
+                return Ok(jwt.clone());
+            }
+        }
+        let (header, payload) = mk_jwt_data(push_resource, subject, opts, now);
+        let jwt = to_signed_jwt(&header, &payload, key)?;
+        self.lock()?.insert(aud, (jwt.clone(), payload.exp));
+        Ok(jwt)
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, HashMap<String, (String, u64)>>> {
+        Ok(err_other!(self.tokens.lock(), "poisoned jwt cache")?)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::base64::base64url_decode;
-    use serde::de::DeserializeOwned;
 
     fn from_b64_json<T: DeserializeOwned>(encoded: &str) -> T {
         let decoded = base64url_decode(encoded).unwrap();
@@ -87,9 +343,9 @@ mod tests {
         let (header, payload) = mk_jwt_data(
             &Url::parse("http://www.www.www").unwrap(),
             &Url::parse("mailto:test@email.test").unwrap(),
-            1,
-        )
-        .unwrap();
+            &JwtOptions::new(60, 10, true).unwrap(),
+            now().unwrap(),
+        );
         let jwt = to_signed_jwt(&header, &payload, &key).unwrap();
 
         let jwt_components: Vec<&str> = jwt.split('.').collect();
@@ -98,11 +354,151 @@ mod tests {
         let header_parsed: JwtHeader = from_b64_json(jwt_components[0]);
         assert_eq!(header, header_parsed);
 
-        let payload_parsed: JwtPayload = from_b64_json(jwt_components[1]);
+        let payload_parsed: Claims = from_b64_json(jwt_components[1]);
         assert_eq!(payload, payload_parsed);
+        assert_eq!(payload.iat, payload.nbf);
+        assert_eq!(payload.exp - payload.iat.unwrap(), 70);
 
         let sig = base64url_decode(jwt_components[2]).unwrap();
         let data = jwt_components[..2].join(".");
         assert!(key.verify(data.as_bytes(), &sig).unwrap())
     }
+
+    #[test]
+    fn jwts_are_cached_by_audience() {
+        let key = Es256::gen().unwrap();
+        let subject = Url::parse("mailto:test@email.test").unwrap();
+        let push1 = Url::parse("https://push.example.net/push/1").unwrap();
+        let push2 = Url::parse("https://push.example.net/push/2").unwrap();
+        let other = Url::parse("https://other.example.net/push/1").unwrap();
+        let cache = VapidJwtCache::default();
+        let opts = JwtOptions::default();
+
+        let jwt = cache.get(&push1, &subject, &opts, &key).unwrap();
+        assert_eq!(cache.get(&push2, &subject, &opts, &key).unwrap(), jwt);
+        let jwt_other = cache.get(&other, &subject, &opts, &key).unwrap();
+        assert_ne!(jwt_other, jwt);
+        let payload: Claims = from_b64_json(jwt_other.split('.').nth(1).unwrap());
+        assert_eq!(payload.aud, "https://other.example.net");
+        assert_eq!(payload.iat, None);
+
+        // about to expire
+        cache.lock().unwrap().get_mut(&audience(&push1)).unwrap().1 = now().unwrap() + 59;
+        assert_ne!(cache.get(&push1, &subject, &opts, &key).unwrap(), jwt);
+    }
+
+    #[test]
+    fn lifetime_is_validated() {
+        assert!(JwtOptions::new(MAX_TTL, 0, false).is_ok());
+        assert!(JwtOptions::new(MAX_TTL - 60, 60, true).is_ok());
+        assert!(JwtOptions::new(MAX_TTL, 1, false).is_err());
+        assert!(JwtOptions::new(MAX_TTL + 1, 0, false).is_err());
+        assert!(JwtOptions::new(0, 0, false).is_err());
+    }
+
+    #[test]
+    fn exp_does_not_overflow() {
+        // 2106-02-07T06:28:16Z does not fit into u32
+        let issued_at = u32::MAX as u64 + 1;
+        let key = Es256::gen().unwrap();
+        let opts = JwtOptions::new(600, 60, true).unwrap();
+        let push_resource = Url::parse("https://push.example.net/p/1").unwrap();
+        let subject = Url::parse("mailto:a@b.test").unwrap();
+        let (header, payload) = mk_jwt_data(&push_resource, &subject, &opts, issued_at);
+        let t = to_signed_jwt(&header, &payload, &key).unwrap();
+        let k = base64url_encode(key.public_key().unwrap());
+        let auth = format!("vapid t={t}, k={k}");
+
+        let claims = verify_vapid_at(&auth, "https://push.example.net", issued_at).unwrap();
+        assert_eq!(claims.exp, issued_at + 600);
+        assert_eq!(claims.iat, Some(issued_at - 60));
+    }
+
+    fn authorization(push_resource: &str, subject: &str, opts: &JwtOptions) -> String {
+        let key = Es256::gen().unwrap();
+        let push_resource = Url::parse(push_resource).unwrap();
+        let subject = Url::parse(subject).unwrap();
+        let (t, k) = mk_vapid_jwt(&push_resource, &subject, opts, &key).unwrap();
+        format!("vapid t={t}, k={k}")
+    }
+
+    #[test]
+    fn vapid_is_verified() {
+        let aud = "https://push.example.net";
+        let opts = JwtOptions::new(600, 60, true).unwrap();
+        let auth = authorization("https://push.example.net/p/1", "mailto:a@b.test", &opts);
+        let claims = verify_vapid(&auth, aud).unwrap();
+        assert_eq!(claims.aud, aud);
+        assert_eq!(claims.sub, "mailto:a@b.test");
+        assert!(verify_vapid(&auth.replacen("vapid", "Vapid", 1), aud).is_ok());
+        assert!(verify_vapid(&auth.replacen("vapid", "VAPID", 1), aud).is_ok());
+
+        let now = now().unwrap();
+        assert!(matches!(
+            verify_vapid(&auth, "https://other.example.net"),
+            Err(VapidError::AudienceMismatch { .. })
+        ));
+        assert!(matches!(
+            verify_vapid_at(&auth, aud, now + 600),
+            Err(VapidError::Expired { .. })
+        ));
+        assert!(matches!(
+            verify_vapid_at(&auth, aud, now - MAX_TTL),
+            Err(VapidError::LifetimeTooLong { .. })
+        ));
+        assert!(matches!(
+            verify_vapid_at(&auth, aud, now - 120),
+            Err(VapidError::NotYetValid { .. })
+        ));
+
+        let auth = authorization("https://push.example.net/p/1", "https://a.test", &opts);
+        assert!(verify_vapid(&auth, aud).is_ok());
+        let auth = authorization("https://push.example.net/p/1", "mailto:nobody", &opts);
+        assert!(matches!(
+            verify_vapid(&auth, aud),
+            Err(VapidError::InvalidSubject(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_vapid_is_rejected() {
+        let aud = "https://push.example.net";
+        let opts = JwtOptions::default();
+        let auth = authorization("https://push.example.net/p/1", "mailto:a@b.test", &opts);
+        let other = authorization("https://push.example.net/p/1", "mailto:a@b.test", &opts);
+
+        assert_eq!(
+            verify_vapid(&auth.replace("vapid", "WebPush"), aud),
+            Err(VapidError::InvalidHeader)
+        );
+        assert_eq!(
+            verify_vapid(&auth.replace(", k=", ", x="), aud),
+            Err(VapidError::InvalidHeader)
+        );
+        // the key of another token
+        let k = other.split_once(", ").unwrap().1;
+        let t = auth.split_once(", ").unwrap().0;
+        assert_eq!(
+            verify_vapid(&format!("{t}, {k}"), aud),
+            Err(VapidError::InvalidSignature)
+        );
+        assert_eq!(
+            verify_vapid(&format!("{t}, k=AAAA"), aud),
+            Err(VapidError::InvalidKey)
+        );
+        assert_eq!(
+            verify_vapid("vapid t=a.b, k=AAAA", aud),
+            Err(VapidError::InvalidToken)
+        );
+        let header = base64url_encode(r#"{"typ":"JWT","alg":"HS256"}"#);
+        let t = t.replacen(
+            t.split('.').next().unwrap(),
+            &format!("vapid t={header}"),
+            1,
+        );
+        assert_eq!(
+            verify_vapid(&format!("{t}, {k}"), aud),
+            Err(VapidError::UnsupportedAlgorithm(String::from("HS256")))
+        );
+    }
 }
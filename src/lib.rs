@@ -1,8 +1,14 @@
 pub mod base64;
 pub mod db;
+pub mod delivery;
 pub mod encr;
 pub mod err;
 pub mod es256;
 pub mod jwt;
+pub mod msg;
+pub mod push;
+pub mod queue;
+pub mod retry;
 pub mod subscription;
+pub mod topic;
 pub mod utils;
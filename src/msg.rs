@@ -0,0 +1,340 @@
+use crate::err::{Error, Result};
+use crate::es256::{check_plaintext_size, MAX_PLAINTEXT_SIZE};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use url::Url;
+
+pub const ICON: &str = "push-small.png";
+/// Appended to the body when it is truncated
+const ELLIPSIS: &str = "…";
+
+/// Text direction of the notification
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Auto,
+    Ltr,
+    Rtl,
+}
+
+/// A button shown on the notification
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Action {
+    /// Identifies the action that was clicked
+    pub action: String,
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    /// Opened when the action is clicked, passed to the service worker in the data
+    #[serde(skip_serializing)]
+    pub url: Option<Url>,
+}
+
+/// Options of the notification as in the `showNotification` of the Notification API,
+/// except for the body which is a part of [Msg]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "camelCase")]
+pub struct NotificationOptions {
+    /// Defaults to [ICON], no icon is shown if null
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub badge: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    /// Notifications with the same tag replace each other
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// Notify again when replacing a notification with the same tag
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub renotify: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub require_interaction: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub silent: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<Action>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    /// Milliseconds since the unix epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// Alternating vibration and pause durations in milliseconds
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub vibrate: Vec<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dir: Option<Direction>,
+}
+
+impl Default for NotificationOptions {
+    fn default() -> Self {
+        Self {
+            icon: Some(ICON.to_string()),
+            badge: None,
+            image: None,
+            tag: None,
+            renotify: None,
+            require_interaction: None,
+            silent: None,
+            actions: Vec::new(),
+            data: None,
+            timestamp: None,
+            vibrate: Vec::new(),
+            lang: None,
+            dir: None,
+        }
+    }
+}
+
+impl NotificationOptions {
+    /// Check the combinations of options that `showNotification` rejects
+    pub fn validate(&self) -> Result<()> {
+        if self.renotify == Some(true) && self.tag.as_deref().is_none_or(str::is_empty) {
+            return Err("renotify requires a tag".into());
+        }
+        if self.silent == Some(true) && !self.vibrate.is_empty() {
+            return Err("silent notification cannot vibrate".into());
+        }
+        if self.actions.iter().any(|a| a.action.is_empty()) {
+            return Err("action without an identifier".into());
+        }
+        Ok(())
+    }
+}
+
+/// A push message that the service worker shows as a notification
+#[derive(Clone, Debug, PartialEq)]
+pub struct Msg {
+    pub title: String,
+    pub body: String,
+    /// Opened when the notification is clicked
+    pub url: Option<Url>,
+    pub options: NotificationOptions,
+}
+
+impl Serialize for Msg {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        #[derive(Serialize)]
+        struct MsgRaw<'a> {
+            title: &'a str,
+            options: MsgOpt<'a>,
+        }
+        #[derive(Serialize)]
+        struct MsgOpt<'a> {
+            body: &'a str,
+            #[serde(flatten)]
+            options: NotificationOptions,
+        }
+        let mut options = self.options.clone();
+        options.data = self.data().map_err(serde::ser::Error::custom)?;
+        let raw = MsgRaw {
+            title: &self.title,
+            options: MsgOpt {
+                body: &self.body,
+                options,
+            },
+        };
+        raw.serialize(serializer)
+    }
+}
+
+impl Msg {
+    /// Message with the default options
+    pub fn new(title: String, body: String) -> Self {
+        Self {
+            title,
+            body,
+            url: None,
+            options: NotificationOptions::default(),
+        }
+    }
+
+    /// Truncate the body so that the message fits into a push message, marking the cut
+    /// with an ellipsis. Fails if the message does not fit even without the body.
+    pub fn truncate_body(&mut self) -> Result<()> {
+        let size = serde_json::to_vec(self)?.len();
+        if size <= MAX_PLAINTEXT_SIZE {
+            return Ok(());
+        }
+        // binary search for the longest prefix of the body that fits
+        let body = std::mem::take(&mut self.body);
+        let (mut fits, mut too_long) = (None, body.len());
+        let mut low = 0;
+        while low < too_long {
+            let mut len = (low + too_long) / 2;
+            while !body.is_char_boundary(len) {
+                len -= 1;
+            }
+            self.body = [&body[..len], ELLIPSIS].concat();
+            match serde_json::to_vec(self)?.len() <= MAX_PLAINTEXT_SIZE {
+                true => {
+                    fits = Some(len);
+                    low = len + body[len..].chars().next().map_or(1, char::len_utf8);
+                }
+                false => too_long = len,
+            }
+        }
+        match fits {
+            Some(len) => {
+                self.body = [&body[..len], ELLIPSIS].concat();
+                Ok(())
+            }
+            None => {
+                self.body = body;
+                Err(Error::PayloadTooLarge {
+                    size,
+                    max: MAX_PLAINTEXT_SIZE,
+                })
+            }
+        }
+    }
+
+    /// The notification data with the urls that the service worker opens on click: `url`
+    /// for the notification itself and `actions` mapping the actions to their urls.
+    fn data(&self) -> Result<Option<Value>> {
+        let actions: Map<String, Value> = self
+            .options
+            .actions
+            .iter()
+            .filter_map(|a| Some((a.action.clone(), json!(a.url.as_ref()?))))
+            .collect();
+        if self.url.is_none() && actions.is_empty() {
+            return Ok(self.options.data.clone());
+        }
+        let mut data = match &self.options.data {
+            Some(Value::Object(data)) => data.clone(),
+            Some(_) => return Err("urls require the data to be an object".into()),
+            None => Map::new(),
+        };
+        if let Some(url) = &self.url {
+            data.insert(String::from("url"), json!(url));
+        }
+        if !actions.is_empty() {
+            data.insert(String::from("actions"), Value::Object(actions));
+        }
+        Ok(Some(Value::Object(data)))
+    }
+}
+
+impl TryFrom<&Msg> for Vec<u8> {
+    type Error = Error;
+
+    /// Validate the message and serialize it as JSON that fits into a push message.
+    fn try_from(msg: &Msg) -> Result<Self> {
+        msg.options.validate()?;
+        msg.data()?;
+        let content = serde_json::to_vec(msg)?;
+        check_plaintext_size(content.len())?;
+        Ok(content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_vec_works() {
+        let msg = Msg::new(String::from("title 1"), String::from("this is a body"));
+        let content = Vec::try_from(&msg).unwrap();
+
+        let content_exp = format!(
+            r#"{{"title":"title 1","options":{{"body":"this is a body","icon":"{ICON}"}}}}"#
+        );
+        assert_eq!(content, content_exp.as_bytes());
+    }
+
+    #[test]
+    fn options_can_be_deserialized() {
+        let input = r#"{"icon":null,"tag":"t","renotify":true,"requireInteraction":true,
+            "actions":[{"action":"ack","title":"Acknowledge"}],"data":{"id":1},
+            "timestamp":1700000000000,"vibrate":[200,100,200],"lang":"fi","dir":"ltr"}"#;
+        let options: NotificationOptions = serde_json::from_str(input).unwrap();
+        assert_eq!(options.icon, None);
+        assert_eq!(options.require_interaction, Some(true));
+        assert_eq!(options.actions[0].title, "Acknowledge");
+        assert_eq!(options.dir, Some(Direction::Ltr));
+
+        let msg = Msg {
+            title: String::from("t"),
+            body: String::from("b"),
+            url: None,
+            options,
+        };
+        let content = String::from_utf8(Vec::try_from(&msg).unwrap()).unwrap();
+        let content_exp = r#"{"title":"t","options":{"body":"b","tag":"t","renotify":true,"requireInteraction":true,"actions":[{"action":"ack","title":"Acknowledge"}],"data":{"id":1},"timestamp":1700000000000,"vibrate":[200,100,200],"lang":"fi","dir":"ltr"}}"#;
+        assert_eq!(content, content_exp);
+
+        let options: NotificationOptions = serde_json::from_str("{}").unwrap();
+        assert_eq!(options, NotificationOptions::default());
+        assert!(serde_json::from_str::<NotificationOptions>(r#"{"dir":"up"}"#).is_err());
+        assert!(serde_json::from_str::<NotificationOptions>(r#"{"sound":"a"}"#).is_err());
+        assert!(serde_json::from_str::<NotificationOptions>(r#"{"vibrate":[-1]}"#).is_err());
+    }
+
+    #[test]
+    fn urls_are_added_to_data() {
+        let mut msg = Msg::new(String::from("t"), String::from("b"));
+        msg.url = Some(Url::parse("https://example.com/dashboard").unwrap());
+        msg.options = serde_json::from_str(
+            r#"{"icon":null,"data":{"id":1},"actions":[
+            {"action":"logs","title":"Logs","url":"https://example.com/logs"},
+            {"action":"ack","title":"Acknowledge"}]}"#,
+        )
+        .unwrap();
+        let content = String::from_utf8(Vec::try_from(&msg).unwrap()).unwrap();
+        let content_exp = r#"{"title":"t","options":{"body":"b","actions":[{"action":"logs","title":"Logs"},{"action":"ack","title":"Acknowledge"}],"data":{"actions":{"logs":"https://example.com/logs"},"id":1,"url":"https://example.com/dashboard"}}}"#;
+        assert_eq!(content, content_exp);
+
+        msg.options.data = Some(json!([1]));
+        assert!(Vec::try_from(&msg).is_err());
+        msg.url = None;
+        msg.options.actions.clear();
+        assert!(Vec::try_from(&msg).is_ok());
+    }
+
+    #[test]
+    fn size_is_limited() {
+        let mut msg = Msg::new(String::from("t"), "ä\n".repeat(MAX_PLAINTEXT_SIZE));
+        assert!(matches!(
+            Vec::try_from(&msg),
+            Err(Error::PayloadTooLarge { max, .. }) if max == MAX_PLAINTEXT_SIZE
+        ));
+
+        msg.truncate_body().unwrap();
+        let content = Vec::try_from(&msg).unwrap();
+        assert!(content.len() <= MAX_PLAINTEXT_SIZE);
+        assert!(content.len() > MAX_PLAINTEXT_SIZE - 4);
+        assert!(msg.body.starts_with("ä\nä"));
+        assert!(msg.body.ends_with(ELLIPSIS));
+
+        let mut msg = Msg::new(String::from("short"), String::from("body"));
+        msg.truncate_body().unwrap();
+        assert_eq!(msg.body, "body");
+
+        let mut msg = Msg::new("t".repeat(MAX_PLAINTEXT_SIZE), String::from("body"));
+        assert!(matches!(
+            msg.truncate_body(),
+            Err(Error::PayloadTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn invalid_combinations_are_rejected() {
+        let mut msg = Msg::new(String::from("t"), String::from("b"));
+        msg.options.renotify = Some(true);
+        assert!(Vec::try_from(&msg).is_err());
+        msg.options.tag = Some(String::from("t"));
+        assert!(Vec::try_from(&msg).is_ok());
+
+        msg.options.silent = Some(true);
+        msg.options.vibrate = vec![100];
+        assert!(Vec::try_from(&msg).is_err());
+    }
+}
@@ -0,0 +1,614 @@
+use crate::base64::base64url_encode;
+use crate::delivery::{Delivery, Outcome, Summary};
+use crate::encr::gen_salt;
+use crate::err::{Error, Result};
+use crate::err_other;
+use crate::es256::{check_plaintext_size, Es256, MAX_PLAINTEXT_SIZE};
+use crate::jwt::{JwtOptions, VapidJwtCache};
+use crate::msg::Msg;
+use crate::queue::{claim_pending, enqueue, finish_delivery, record_attempt, PendingDelivery};
+use crate::retry::RetryPolicy;
+use crate::subscription::{delete_subscription, ContentEncoding, Subscription};
+use crate::utils::{get_var, parse_var_or};
+use deadpool_sqlite::Pool;
+use futures_util::{stream, StreamExt};
+use reqwest::header::{HeaderMap, AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::{Client, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+use url::Url;
+
+const DEFAULT_CONCURRENCY: usize = 16;
+
+/// Urgency of a push message as described in rfc8030 section 5.3
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Urgency {
+    VeryLow,
+    Low,
+    Normal,
+    High,
+}
+
+impl Urgency {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Urgency::VeryLow => "very-low",
+            Urgency::Low => "low",
+            Urgency::Normal => "normal",
+            Urgency::High => "high",
+        }
+    }
+}
+
+impl fmt::Display for Urgency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Urgency {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "very-low" => Ok(Urgency::VeryLow),
+            "low" => Ok(Urgency::Low),
+            "normal" => Ok(Urgency::Normal),
+            "high" => Ok(Urgency::High),
+            _ => Err(format!("invalid urgency '{s}'").into()),
+        }
+    }
+}
+
+/// Topic of a push message as described in rfc8030 section 5.4: a message that is still
+/// pending at the push service is replaced by a newer one with the same topic. Note that
+/// this is unrelated to the topics that subscriptions opt into (see [Target]).
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct PushTopic(String);
+
+impl PushTopic {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl TryFrom<String> for PushTopic {
+    type Error = Error;
+
+    /// At most 32 characters from the URL and filename safe base64 alphabet
+    fn try_from(topic: String) -> Result<Self> {
+        let is_base64url = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        match !topic.is_empty() && topic.len() <= 32 && topic.chars().all(is_base64url) {
+            true => Ok(Self(topic)),
+            false => Err(format!("invalid topic '{topic}'").into()),
+        }
+    }
+}
+
+impl FromStr for PushTopic {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::try_from(s.to_string())
+    }
+}
+
+/// How the plaintext is padded before encryption (rfc8188 section 2) to hide its length
+/// from those who observe the traffic to the push services
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Padding {
+    #[default]
+    None,
+    /// Pad to the next power of two, starting from 128 bytes
+    PadToBucket,
+    /// Pad to the maximum size of a push message
+    PadToMax,
+}
+
+impl Padding {
+    const MIN_BUCKET: usize = 128;
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Padding::None => "none",
+            Padding::PadToBucket => "pad-to-bucket",
+            Padding::PadToMax => "pad-to-max",
+        }
+    }
+
+    /// Length of the padding for a plaintext of length `len`, never exceeding the space left
+    /// in a push message
+    pub fn len_for(&self, len: usize) -> usize {
+        let padded = match self {
+            Padding::None => len,
+            Padding::PadToBucket => len.max(Self::MIN_BUCKET).next_power_of_two(),
+            Padding::PadToMax => MAX_PLAINTEXT_SIZE,
+        };
+        padded.min(MAX_PLAINTEXT_SIZE).saturating_sub(len)
+    }
+}
+
+impl fmt::Display for Padding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Padding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Padding::None),
+            "pad-to-bucket" => Ok(Padding::PadToBucket),
+            "pad-to-max" => Ok(Padding::PadToMax),
+            _ => Err(format!("invalid padding '{s}'").into()),
+        }
+    }
+}
+
+/// Options for the delivery of a push message (rfc8030 section 5.2 - 5.4)
+#[derive(Clone, Debug, PartialEq)]
+pub struct PushOptions {
+    /// How long (in seconds) the push service should retain the message
+    pub ttl: usize,
+    /// The push service default (normal) is used if not set
+    pub urgency: Option<Urgency>,
+    /// Replaces pending messages with the same topic if set
+    pub topic: Option<PushTopic>,
+    /// The padding of the [Sender] is used if not set
+    pub padding: Option<Padding>,
+}
+
+/// The subscriptions that a message is delivered to
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Target {
+    /// Names or glob patterns of the subscriptions, all of them if empty
+    pub names: Vec<String>,
+    /// Only the subscribers of the topic, if set
+    pub topic: Option<String>,
+}
+
+impl Target {
+    /// Does the target include all the subscriptions
+    pub fn is_all(&self) -> bool {
+        self.names.is_empty() && self.topic.is_none()
+    }
+}
+
+impl Default for PushOptions {
+    fn default() -> Self {
+        Self {
+            ttl: 10,
+            urgency: None,
+            topic: None,
+            padding: None,
+        }
+    }
+}
+
+pub struct VapidConfig {
+    key: Es256,
+    subject: Url,
+    jwt_options: JwtOptions,
+    jwts: VapidJwtCache,
+}
+
+impl VapidConfig {
+    pub fn from_env() -> Result<Self> {
+        let public_key = get_var("VAPID_PUBLIC_KEY")?;
+        let private_key = get_var("VAPID_PRIVATE_KEY")?;
+        let subject = err_other!(Url::parse(&get_var("VAPID_SUBJECT")?))?;
+        let key = Es256::try_from((private_key.as_str(), public_key.as_str()))?;
+        let jwt_options = JwtOptions::from_env()?;
+        Ok(Self {
+            key,
+            subject,
+            jwt_options,
+            jwts: VapidJwtCache::default(),
+        })
+    }
+
+    pub fn public_key(&self) -> Result<String> {
+        self.key.public_key().map(base64url_encode)
+    }
+
+    /// JWT for the push resource (rfc8292 section 2), shared by the push resources of the
+    /// same origin
+    fn jwt(&self, push_resource: &Url) -> Result<String> {
+        self.jwts
+            .get(push_resource, &self.subject, &self.jwt_options, &self.key)
+    }
+}
+
+/// The parameters of the content encoding sent in the headers
+enum EncodingParams {
+    Aes128Gcm,
+    /// The salt and the public key of the sender for the legacy encoding
+    AesGcm {
+        salt: String,
+        dh: String,
+    },
+}
+
+/// Headers for the push notification query
+fn construct_headers(
+    jwt: &str,
+    k: &str,
+    len: usize,
+    opts: &PushOptions,
+    encoding: &EncodingParams,
+) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    let auth = format!("vapid t={}, k={}", jwt, k);
+    headers.insert(AUTHORIZATION, auth.try_into()?);
+    headers.insert(CONTENT_LENGTH, len.into());
+    headers.insert(CONTENT_TYPE, "application/octet-stream".try_into()?);
+    match encoding {
+        EncodingParams::Aes128Gcm => {
+            headers.insert("Crypto-Key", format!("p256ecdsa={}", k).try_into()?);
+            headers.insert(CONTENT_ENCODING, "aes128gcm".try_into()?);
+        }
+        EncodingParams::AesGcm { salt, dh } => {
+            let crypto_key = format!("dh={dh};p256ecdsa={k}");
+            headers.insert("Crypto-Key", crypto_key.try_into()?);
+            headers.insert("Encryption", format!("salt={salt}").try_into()?);
+            headers.insert(CONTENT_ENCODING, "aesgcm".try_into()?);
+        }
+    }
+    headers.insert("TTL", opts.ttl.into());
+    if let Some(urgency) = opts.urgency {
+        headers.insert("Urgency", urgency.as_str().try_into()?);
+    }
+    if let Some(topic) = &opts.topic {
+        headers.insert("Topic", topic.as_str().try_into()?);
+    }
+    Ok(headers)
+}
+
+/// The response of the push service to a single push, for diagnosing deliveries
+#[derive(Debug, Serialize)]
+pub struct PushResponse {
+    pub status: u16,
+    /// Repeated headers are joined with commas
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+impl PushResponse {
+    pub async fn read(resp: Response) -> Result<Self> {
+        let status = resp.status().as_u16();
+        let mut headers = BTreeMap::<_, String>::new();
+        for (name, value) in resp.headers() {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            headers
+                .entry(name.to_string())
+                .and_modify(|v| *v = format!("{v}, {value}"))
+                .or_insert_with(|| value.to_string());
+        }
+        let body = resp.text().await?;
+        Ok(Self {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+impl fmt::Display for PushResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = StatusCode::from_u16(self.status)
+            .ok()
+            .and_then(|s| s.canonical_reason());
+        write!(f, "{} {}", self.status, reason.unwrap_or_default())?;
+        for (name, value) in &self.headers {
+            write!(f, "\n{name}: {value}")?;
+        }
+        match self.body.as_str() {
+            "" => Ok(()),
+            body => write!(f, "\n\n{body}"),
+        }
+    }
+}
+
+/// Delivers push messages to the subscriptions. A single [Client] is shared between the
+/// requests so that connections to the push services are reused.
+pub struct Sender {
+    client: Client,
+    vapid: VapidConfig,
+    concurrency: usize,
+    retry: RetryPolicy,
+    padding: Padding,
+}
+
+impl Sender {
+    pub fn from_env() -> Result<Self> {
+        let concurrency = parse_var_or("PUSH_CONCURRENCY", DEFAULT_CONCURRENCY)?;
+        if concurrency == 0 {
+            return Err("PUSH_CONCURRENCY must be positive".into());
+        }
+        let vapid = VapidConfig::from_env()?;
+        let retry = RetryPolicy::from_env()?;
+        let padding = parse_var_or("PUSH_PADDING", Padding::default())?;
+        Ok(Self {
+            client: Client::new(),
+            vapid,
+            concurrency,
+            retry,
+            padding,
+        })
+    }
+
+    /// Request for push message delivery as described in rfc8030 section 5
+    pub async fn send_notification(
+        &self,
+        sub: &Subscription,
+        content: &[u8],
+        opts: &PushOptions,
+    ) -> Result<Response> {
+        let vapid = &self.vapid;
+        let jwt = vapid.jwt(sub.endpoint())?;
+
+        let local_key = Es256::gen()?;
+        let salt = gen_salt::<16>()?;
+        let padding = opts.padding.unwrap_or(self.padding).len_for(content.len());
+        let (payload, encoding) = match sub.content_encoding() {
+            ContentEncoding::Aes128Gcm => {
+                let payload =
+                    local_key.mk_content(sub.p256dh(), sub.auth(), &salt, content, padding)?;
+                (payload, EncodingParams::Aes128Gcm)
+            }
+            ContentEncoding::AesGcm => {
+                let payload = local_key.mk_content_aesgcm(
+                    sub.p256dh(),
+                    sub.auth(),
+                    &salt,
+                    content,
+                    padding,
+                )?;
+                let salt = base64url_encode(salt);
+                let dh = base64url_encode(local_key.public_key()?);
+                (payload, EncodingParams::AesGcm { salt, dh })
+            }
+        };
+
+        let k = vapid.public_key()?;
+        let headers = construct_headers(&jwt, &k, payload.len(), opts, &encoding)?;
+        let req = self
+            .client
+            .post(sub.endpoint().clone())
+            .body(payload)
+            .headers(headers);
+
+        Ok(req.send().await?)
+    }
+
+    /// Send a diagnostic message titled `title` to the subscription and return the response
+    /// of the push service. Unlike with [Sender::send_notifications], the message is not
+    /// queued, failures are not retried and expired subscriptions are not removed.
+    pub async fn send_diagnostic(&self, sub: &Subscription, title: &str) -> Result<PushResponse> {
+        let msg = Msg::new(title.to_string(), format!("Test push to {}", sub.name()));
+        let content = Vec::try_from(&msg)?;
+        let resp = self
+            .send_notification(sub, &content, &PushOptions::default())
+            .await?;
+        PushResponse::read(resp).await
+    }
+
+    /// [Sender::send_notification] and log the response. Each attempt is recorded in the
+    /// queue and transient errors are retried according to the [RetryPolicy]. The attempts
+    /// made before a restart count towards the maximum of the policy.
+    async fn deliver(&self, pool: &Pool, delivery: &PendingDelivery) -> Outcome {
+        let sub = delivery.subscription();
+        if delivery.attempts() >= self.retry.max_attempts() {
+            let e = format!("gave up after {} attempts", delivery.attempts());
+            return Outcome::Failed(e.into());
+        }
+        let mut attempt = delivery.attempts() + 1;
+        let resp = loop {
+            if let Err(e) = record_attempt(pool, delivery.id()).await {
+                return Outcome::Failed(e);
+            }
+            let sent = self.send_notification(sub, delivery.content(), delivery.options());
+            let resp = match sent.await {
+                Ok(resp) => resp,
+                Err(e) => return Outcome::Failed(e),
+            };
+            let Some(delay) = self.retry.delay(attempt, resp.status(), resp.headers()) else {
+                break resp;
+            };
+            warn!(
+                "Push to {} returned {}, retrying in {delay:?} (attempt {attempt}/{})",
+                sub.name(),
+                resp.status(),
+                self.retry.max_attempts()
+            );
+            sleep(delay).await;
+            attempt += 1;
+        };
+        let status = resp.status();
+        match resp.text().await.as_ref().map(|s| s.as_str()) {
+            Ok("") => {}
+            Ok(s) => info!("Response from {}: {s}", sub.name()),
+            Err(e) => error!("Non-renderable response from {}: {e}", sub.name()),
+        }
+        Outcome::from(status)
+    }
+
+    /// Enqueue the message for the subscriptions from `pool` and deliver it with
+    /// [Sender::drain]. Only the subscriptions included in the [Target] receive the message.
+    /// Content that does not fit into a push message is rejected before enqueuing it.
+    pub async fn send_notifications(
+        &self,
+        pool: &Pool,
+        content: &[u8],
+        opts: &PushOptions,
+        target: &Target,
+        encryption_key: [u8; 16],
+    ) -> Result<Summary> {
+        check_plaintext_size(content.len())?;
+        let message_id = enqueue(pool, content.to_vec(), opts.clone(), target.clone()).await?;
+        self.drain(pool, encryption_key, Some(message_id)).await
+    }
+
+    /// Deliver the pending messages in the queue (only the given message if `message_id` is
+    /// set), recording the final status of each delivery, and log the results. At most
+    /// `PUSH_CONCURRENCY` requests are in flight at once. A failure with one subscription
+    /// does not prevent delivery to the others. Subscriptions that have expired are removed
+    /// from the database. The deliveries are claimed first, so that concurrent drains do not
+    /// send them twice.
+    pub async fn drain(
+        &self,
+        pool: &Pool,
+        encryption_key: [u8; 16],
+        message_id: Option<u32>,
+    ) -> Result<Summary> {
+        let claimed = claim_pending(pool, encryption_key, message_id).await?;
+        let mut outcomes = stream::iter(claimed)
+            .map(|d| async move {
+                let (id, name, endpoint, outcome) = match d {
+                    Ok(d) => {
+                        let outcome = self.deliver(pool, &d).await;
+                        let sub = d.subscription();
+                        let endpoint = Some(sub.endpoint().clone());
+                        (d.id(), sub.name().to_string(), endpoint, outcome)
+                    }
+                    Err(d) => (d.id, d.name, d.endpoint, Outcome::Failed(d.error)),
+                };
+                let delivery = Delivery {
+                    name,
+                    endpoint,
+                    outcome,
+                };
+                (id, delivery)
+            })
+            .buffer_unordered(self.concurrency);
+
+        let mut summary = Summary::default();
+        while let Some((id, delivery)) = outcomes.next().await {
+            let name = &delivery.name;
+            let (status, response_status, err) = delivery.outcome.to_record();
+            if let Err(e) = finish_delivery(pool, id, status, response_status, err).await {
+                error!("Recording delivery to {name} failed: {e}");
+            }
+            if let (Outcome::Rejected(status), Some(endpoint)) =
+                (&delivery.outcome, &delivery.endpoint)
+            {
+                if is_expired(*status) {
+                    match delete_subscription(pool, endpoint).await {
+                        // None if it was removed already, e.g. along with another
+                        // subscription to the same endpoint
+                        Ok(_) => summary.pruned += 1,
+                        Err(e) => error!("Removing expired subscription {name} failed: {e}"),
+                    }
+                }
+            }
+            match delivery.outcome {
+                Outcome::Failed(_) => error!("{delivery}"),
+                _ => info!("{delivery}"),
+            }
+            summary.deliveries.push(delivery);
+        }
+        info!("{summary}");
+        Ok(summary)
+    }
+}
+
+/// Push service responses indicating that the subscription has expired (rfc8030 section 8.1)
+fn is_expired(status: StatusCode) -> bool {
+    status == StatusCode::NOT_FOUND || status == StatusCode::GONE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_expired_matches_rfc8030() {
+        assert!(is_expired(StatusCode::NOT_FOUND));
+        assert!(is_expired(StatusCode::GONE));
+        assert!(!is_expired(StatusCode::CREATED));
+        assert!(!is_expired(StatusCode::TOO_MANY_REQUESTS));
+    }
+
+    #[test]
+    fn urgency_is_validated() {
+        let urgency: Urgency = serde_json::from_str(r#""very-low""#).unwrap();
+        assert_eq!(urgency, Urgency::VeryLow);
+        assert_eq!(urgency.as_str().parse::<Urgency>().unwrap(), urgency);
+        assert!(serde_json::from_str::<Urgency>(r#""urgent""#).is_err());
+        assert!("urgent".parse::<Urgency>().is_err());
+    }
+
+    #[test]
+    fn push_topic_is_validated() {
+        assert!("backup_status-1".parse::<PushTopic>().is_ok());
+        assert!("a".repeat(32).parse::<PushTopic>().is_ok());
+        assert!("a".repeat(33).parse::<PushTopic>().is_err());
+        assert!("".parse::<PushTopic>().is_err());
+        assert!("backup status".parse::<PushTopic>().is_err());
+        assert!("backup+status".parse::<PushTopic>().is_err());
+        assert!(serde_json::from_str::<PushTopic>(r#""ci""#).is_ok());
+        assert!(serde_json::from_str::<PushTopic>(r#""c/i""#).is_err());
+    }
+
+    #[test]
+    fn padding_hides_length() {
+        assert_eq!(Padding::None.len_for(100), 0);
+        assert_eq!(Padding::PadToBucket.len_for(0), 128);
+        assert_eq!(Padding::PadToBucket.len_for(100), 28);
+        assert_eq!(Padding::PadToBucket.len_for(128), 0);
+        assert_eq!(Padding::PadToBucket.len_for(129), 127);
+        assert_eq!(
+            Padding::PadToBucket.len_for(3000),
+            MAX_PLAINTEXT_SIZE - 3000
+        );
+        assert_eq!(Padding::PadToMax.len_for(100), MAX_PLAINTEXT_SIZE - 100);
+        assert_eq!(Padding::PadToMax.len_for(MAX_PLAINTEXT_SIZE), 0);
+        assert_eq!(
+            "pad-to-bucket".parse::<Padding>().unwrap(),
+            Padding::PadToBucket
+        );
+        assert!("bucket".parse::<Padding>().is_err());
+    }
+
+    #[test]
+    fn construct_headers_works() {
+        let opts = PushOptions {
+            ttl: 60,
+            urgency: Some(Urgency::High),
+            topic: Some("backup-status".parse().unwrap()),
+            padding: None,
+        };
+        let encoding = EncodingParams::Aes128Gcm;
+        let headers = construct_headers("jwt", "k", 144, &opts, &encoding).unwrap();
+        assert_eq!(headers["Authorization"], "vapid t=jwt, k=k");
+        assert_eq!(headers["Crypto-Key"], "p256ecdsa=k");
+        assert_eq!(headers["Content-Encoding"], "aes128gcm");
+        assert!(!headers.contains_key("Encryption"));
+        assert_eq!(headers["TTL"], "60");
+        assert_eq!(headers["Urgency"], "high");
+        assert_eq!(headers["Topic"], "backup-status");
+
+        let opts = PushOptions::default();
+        let headers = construct_headers("jwt", "k", 144, &opts, &encoding).unwrap();
+        assert!(!headers.contains_key("Urgency"));
+        assert!(!headers.contains_key("Topic"));
+
+        let encoding = EncodingParams::AesGcm {
+            salt: String::from("salt"),
+            dh: String::from("dh"),
+        };
+        let headers = construct_headers("jwt", "k", 144, &opts, &encoding).unwrap();
+        assert_eq!(headers["Crypto-Key"], "dh=dh;p256ecdsa=k");
+        assert_eq!(headers["Encryption"], "salt=salt");
+        assert_eq!(headers["Content-Encoding"], "aesgcm");
+    }
+}
@@ -0,0 +1,630 @@
+use crate::err::{Error, Result};
+use crate::push::{PushOptions, PushTopic, Target};
+use crate::subscription::Subscription;
+use crate::topic::insert_topics;
+use deadpool_sqlite::rusqlite::{Connection, OptionalExtension, Row, TransactionBehavior};
+use deadpool_sqlite::Pool;
+use std::{fmt, slice};
+use url::Url;
+
+/// Seconds after which a delivery still in flight is considered abandoned, e.g. because
+/// the process delivering it crashed, and is claimed again. Each attempt renews the claim,
+/// so this has to exceed the delay between the attempts (`PUSH_RETRY_MAX_DELAY_MS`).
+const CLAIM_TIMEOUT_SECS: u32 = 900;
+
+/// State of a message delivery to a single subscription
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeliveryStatus {
+    Pending,
+    /// Claimed by a drain that is delivering it
+    InFlight,
+    Delivered,
+    Rejected,
+    Failed,
+}
+
+impl DeliveryStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::InFlight => "in_flight",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Rejected => "rejected",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+}
+
+impl fmt::Display for DeliveryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A message waiting to be delivered to a subscription
+#[derive(Debug)]
+pub struct PendingDelivery {
+    id: u32,
+    message_id: u32,
+    attempts: u32,
+    content: Vec<u8>,
+    options: PushOptions,
+    subscription: Subscription,
+}
+
+impl PendingDelivery {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn message_id(&self) -> u32 {
+        self.message_id
+    }
+
+    /// Number of attempts made before this one, e.g. before a restart
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
+
+    pub fn options(&self) -> &PushOptions {
+        &self.options
+    }
+
+    pub fn subscription(&self) -> &Subscription {
+        &self.subscription
+    }
+
+    /// Read a delivery from a row of [query_pending]
+    fn from_row(r: &Row, key: &[u8; 16]) -> Result<Self> {
+        let urgency = r.get::<_, Option<String>>(13)?;
+        let topic = r.get::<_, Option<String>>(14)?;
+        let padding = r.get::<_, Option<String>>(15)?;
+        Ok(Self {
+            subscription: Subscription::from_row(r, key)?,
+            id: r.get(8)?,
+            message_id: r.get(9)?,
+            attempts: r.get(10)?,
+            content: r.get(11)?,
+            options: PushOptions {
+                ttl: r.get(12)?,
+                urgency: urgency.map(|u| u.parse()).transpose()?,
+                topic: topic.map(PushTopic::try_from).transpose()?,
+                padding: padding.map(|p| p.parse()).transpose()?,
+            },
+        })
+    }
+}
+
+/// A pending delivery that cannot be sent as its row could not be read, e.g. because the
+/// stored key of the subscription is malformed
+#[derive(Debug)]
+pub struct UnreadableDelivery {
+    pub id: u32,
+    pub name: String,
+    pub endpoint: Option<Url>,
+    pub error: Error,
+}
+
+impl UnreadableDelivery {
+    /// The identifying columns of a row of [query_pending] that failed with `error`
+    fn from_row(r: &Row, error: Error) -> Result<Self> {
+        Ok(Self {
+            id: r.get(8)?,
+            name: r.get(1)?,
+            endpoint: r.get::<_, String>(0).ok().and_then(|e| Url::parse(&e).ok()),
+            error,
+        })
+    }
+}
+
+/// Store the message and create a pending delivery for each of the subscriptions in the
+/// `target`. Names in the target are matched as [GLOB](https://sqlite.org/lang_expr.html#glob)
+/// patterns (e.g. `phone-*`). The topic of the target is created if it does not exist.
+fn insert_message(
+    conn: &mut Connection,
+    content: &[u8],
+    opts: &PushOptions,
+    target: &Target,
+) -> Result<u32> {
+    let tx = conn.transaction()?;
+    let message_id: u32 = tx.query_row(
+        "INSERT INTO message (content, ttl, urgency, topic, padding)
+        VALUES (?1, ?2, ?3, ?4, ?5) RETURNING id",
+        (
+            content,
+            opts.ttl,
+            opts.urgency.map(|u| u.as_str()),
+            opts.topic.as_ref().map(|t| t.as_str()),
+            opts.padding.map(|p| p.as_str()),
+        ),
+        |r| r.get(0),
+    )?;
+    if let Some(topic) = &target.topic {
+        insert_topics(&tx, slice::from_ref(topic))?;
+    }
+    {
+        let mut stmt = tx.prepare(
+            "INSERT OR IGNORE INTO delivery (message_id, subscription_id)
+            SELECT ?1, s.id FROM subscription s
+            WHERE (?2 IS NULL OR s.name GLOB ?2)
+            AND (?3 IS NULL OR s.id IN (
+                SELECT st.subscription_id FROM subscription_topic st
+                JOIN topic t ON t.id = st.topic_id
+                WHERE t.name = ?3
+            ))",
+        )?;
+        match target.names.is_empty() {
+            true => {
+                stmt.execute((message_id, None::<&str>, &target.topic))?;
+            }
+            false => {
+                for pattern in &target.names {
+                    stmt.execute((message_id, pattern, &target.topic))?;
+                }
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(message_id)
+}
+
+/// Pending deliveries, for the given message only if `message_id` is set. Deliveries to
+/// subscriptions that have been removed in the meantime are skipped. Each row is read on its
+/// own, so that one that cannot be read does not prevent the others from being delivered.
+fn query_pending(
+    conn: &Connection,
+    key: &[u8; 16],
+    message_id: Option<u32>,
+) -> Result<Vec<std::result::Result<PendingDelivery, UnreadableDelivery>>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {}, d.id, d.message_id, d.attempts, m.content, m.ttl, m.urgency, m.topic,
+            m.padding
+        FROM delivery d
+        JOIN subscription s ON s.id = d.subscription_id
+        JOIN message m ON m.id = d.message_id
+        WHERE d.status = ?1 AND (?2 IS NULL OR d.message_id = ?2)
+        ORDER BY d.id",
+        Subscription::COLUMNS
+    ))?;
+    let mut rows = stmt.query((DeliveryStatus::Pending.as_str(), message_id))?;
+    let mut v = vec![];
+    while let Some(r) = rows.next()? {
+        match PendingDelivery::from_row(r, key) {
+            Ok(delivery) => v.push(Ok(delivery)),
+            Err(e) => v.push(Err(UnreadableDelivery::from_row(r, e)?)),
+        }
+    }
+    Ok(v)
+}
+
+/// [query_pending] and mark the deliveries in flight, so that a concurrent drain (e.g. by
+/// another process sharing the database) does not send them again. The write lock is taken
+/// first, so the deliveries are claimed by one drain only. Claims older than
+/// [CLAIM_TIMEOUT_SECS] are released before that.
+fn claim_deliveries(
+    conn: &mut Connection,
+    key: &[u8; 16],
+    message_id: Option<u32>,
+) -> Result<Vec<std::result::Result<PendingDelivery, UnreadableDelivery>>> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    tx.execute(
+        "UPDATE delivery SET status = ?1
+        WHERE status = ?2 AND updated < datetime('now', ?3)",
+        (
+            DeliveryStatus::Pending.as_str(),
+            DeliveryStatus::InFlight.as_str(),
+            format!("-{CLAIM_TIMEOUT_SECS} seconds"),
+        ),
+    )?;
+    let pending = query_pending(&tx, key, message_id)?;
+    {
+        let mut stmt = tx.prepare(
+            "UPDATE delivery SET status = ?2, updated = CURRENT_TIMESTAMP WHERE id = ?1",
+        )?;
+        for delivery in &pending {
+            let id = match delivery {
+                Ok(d) => d.id,
+                Err(d) => d.id,
+            };
+            stmt.execute((id, DeliveryStatus::InFlight.as_str()))?;
+        }
+    }
+    tx.commit()?;
+    Ok(pending)
+}
+
+fn increment_attempts(conn: &Connection, id: u32) -> Result<()> {
+    conn.execute(
+        "UPDATE delivery SET attempts = attempts + 1, updated = CURRENT_TIMESTAMP WHERE id = ?1",
+        [id],
+    )?;
+    Ok(())
+}
+
+fn update_status(
+    conn: &Connection,
+    id: u32,
+    status: DeliveryStatus,
+    response_status: Option<u16>,
+    error: Option<String>,
+) -> Result<()> {
+    conn.execute(
+        "UPDATE delivery
+        SET status = ?2, response_status = ?3, error = ?4, updated = CURRENT_TIMESTAMP
+        WHERE id = ?1",
+        (id, status.as_str(), response_status, error),
+    )?;
+    Ok(())
+}
+
+fn query_status(conn: &Connection, id: u32) -> Result<Option<(DeliveryStatus, u32)>> {
+    let row = conn
+        .query_row(
+            "SELECT status, attempts FROM delivery WHERE id = ?1",
+            [id],
+            |r| Ok((r.get::<_, String>(0)?, r.get(1)?)),
+        )
+        .optional()?;
+    let Some((status, attempts)) = row else {
+        return Ok(None);
+    };
+    let status = match status.as_str() {
+        "pending" => DeliveryStatus::Pending,
+        "in_flight" => DeliveryStatus::InFlight,
+        "delivered" => DeliveryStatus::Delivered,
+        "rejected" => DeliveryStatus::Rejected,
+        "failed" => DeliveryStatus::Failed,
+        s => return Err(format!("invalid delivery status '{s}'").into()),
+    };
+    Ok(Some((status, attempts)))
+}
+
+/// Enqueue `content` to be delivered to the existing subscriptions in the `target`.
+/// Returns the id of the message.
+pub async fn enqueue(
+    pool: &Pool,
+    content: Vec<u8>,
+    opts: PushOptions,
+    target: Target,
+) -> Result<u32> {
+    let conn = pool.get().await?;
+    conn.interact(move |c| insert_message(c, &content, &opts, &target))
+        .await?
+}
+
+/// Claim the [PendingDelivery]s, only for the given message if `message_id` is set. The
+/// claimed deliveries are no longer pending, see [claim_deliveries].
+pub async fn claim_pending(
+    pool: &Pool,
+    key: [u8; 16],
+    message_id: Option<u32>,
+) -> Result<Vec<std::result::Result<PendingDelivery, UnreadableDelivery>>> {
+    let conn = pool.get().await?;
+    conn.interact(move |c| claim_deliveries(c, &key, message_id))
+        .await?
+}
+
+/// Record that a delivery attempt is being made.
+pub async fn record_attempt(pool: &Pool, id: u32) -> Result<()> {
+    let conn = pool.get().await?;
+    conn.interact(move |c| increment_attempts(c, id)).await?
+}
+
+/// Record the final status of the delivery along with the push service response status
+/// or the error that prevented the delivery.
+pub async fn finish_delivery(
+    pool: &Pool,
+    id: u32,
+    status: DeliveryStatus,
+    response_status: Option<u16>,
+    error: Option<String>,
+) -> Result<()> {
+    let conn = pool.get().await?;
+    conn.interact(move |c| update_status(c, id, status, response_status, error))
+        .await?
+}
+
+/// Status and the number of attempts of the delivery, if it exists.
+pub async fn get_delivery_status(pool: &Pool, id: u32) -> Result<Option<(DeliveryStatus, u32)>> {
+    let conn = pool.get().await?;
+    conn.interact(move |c| query_status(c, id)).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::push::{Padding, Urgency};
+    use crate::topic::set_topics;
+
+    const KEY: [u8; 16] = [7; 16];
+
+    fn test_db() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&mut conn).unwrap();
+        conn
+    }
+
+    /// The readable deliveries of [query_pending]
+    fn readable(conn: &Connection, message_id: Option<u32>) -> Vec<PendingDelivery> {
+        let pending = query_pending(conn, &KEY, message_id).unwrap();
+        pending.into_iter().map(|d| d.unwrap()).collect()
+    }
+
+    /// The readable deliveries of [claim_deliveries]
+    fn claim(conn: &mut Connection, message_id: Option<u32>) -> Vec<PendingDelivery> {
+        let claimed = claim_deliveries(conn, &KEY, message_id).unwrap();
+        claimed.into_iter().map(|d| d.unwrap()).collect()
+    }
+
+    fn target_names(names: &[&str]) -> Target {
+        Target {
+            names: names.iter().map(|n| n.to_string()).collect(),
+            topic: None,
+        }
+    }
+
+    fn insert_subscription(conn: &Connection, name: &str) -> u32 {
+        // keys from https://www.rfc-editor.org/rfc/rfc8291#appendix-A
+        let sub: Subscription = serde_json::from_str(&format!(
+            r#"{{
+                "endpoint": "https://push.example.net/push/{name}",
+                "name": "{name}",
+                "expirationTime": null,
+                "keys": {{
+                    "auth": "BTBZMqHH6r4Tts7J_aSIgg",
+                    "p256dh": "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4"
+                }}
+            }}"#
+        ))
+        .unwrap();
+        let (salt, auth_encr, tag) = sub.encrypted_auth(&KEY).unwrap();
+        let p256dh = Vec::try_from(sub.p256dh()).unwrap();
+        conn.query_row(
+            "INSERT INTO subscription (endpoint, name, auth_encr, salt, tag, p256dh)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6) RETURNING id",
+            (sub.endpoint().as_str(), name, auth_encr, salt, tag, p256dh),
+            |r| r.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn messages_are_enqueued_for_all_subscriptions() {
+        let mut conn = test_db();
+        insert_subscription(&conn, "first");
+        insert_subscription(&conn, "second");
+        let message_id = insert_message(
+            &mut conn,
+            b"content",
+            &PushOptions::default(),
+            &Target::default(),
+        )
+        .unwrap();
+
+        let pending = readable(&conn, Some(message_id));
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].subscription().name(), "first");
+        assert_eq!(pending[1].subscription().name(), "second");
+        assert_eq!(pending[0].content(), b"content");
+        assert_eq!(pending[0].options(), &PushOptions::default());
+        assert_eq!(
+            pending[0].subscription().auth(),
+            &[5, 48, 89, 50, 161, 199, 234, 190, 19, 182, 206, 201, 253, 164, 136, 130]
+        );
+    }
+
+    #[test]
+    fn finished_deliveries_are_not_pending() {
+        let mut conn = test_db();
+        insert_subscription(&conn, "first");
+        insert_subscription(&conn, "second");
+        let first_message = insert_message(
+            &mut conn,
+            b"first",
+            &PushOptions::default(),
+            &Target::default(),
+        )
+        .unwrap();
+        insert_message(
+            &mut conn,
+            b"second",
+            &PushOptions::default(),
+            &Target::default(),
+        )
+        .unwrap();
+        assert_eq!(readable(&conn, None).len(), 4);
+
+        let id = readable(&conn, Some(first_message))[0].id();
+        increment_attempts(&conn, id).unwrap();
+        increment_attempts(&conn, id).unwrap();
+        update_status(&conn, id, DeliveryStatus::Delivered, Some(201), None).unwrap();
+
+        let pending = readable(&conn, None);
+        assert_eq!(pending.len(), 3);
+        assert!(pending.iter().all(|d| d.id() != id));
+        let status = query_status(&conn, id).unwrap();
+        assert_eq!(status, Some((DeliveryStatus::Delivered, 2)));
+    }
+
+    #[test]
+    fn deliveries_are_claimed_once() {
+        let mut conn = test_db();
+        insert_subscription(&conn, "first");
+        insert_subscription(&conn, "second");
+        let message_id = insert_message(
+            &mut conn,
+            b"content",
+            &PushOptions::default(),
+            &Target::default(),
+        )
+        .unwrap();
+
+        let claimed = claim(&mut conn, None);
+        assert_eq!(claimed.len(), 2);
+        assert!(claim(&mut conn, Some(message_id)).is_empty());
+        let status = query_status(&conn, claimed[0].id()).unwrap();
+        assert_eq!(status, Some((DeliveryStatus::InFlight, 0)));
+
+        // as if the process delivering the first one had crashed long ago
+        conn.execute(
+            "UPDATE delivery SET updated = datetime('now', '-1 hour') WHERE id = ?1",
+            [claimed[0].id()],
+        )
+        .unwrap();
+        let reclaimed = claim(&mut conn, None);
+        assert_eq!(reclaimed.len(), 1);
+        assert_eq!(reclaimed[0].id(), claimed[0].id());
+    }
+
+    #[test]
+    fn unreadable_deliveries_do_not_block_the_others() {
+        let mut conn = test_db();
+        let broken = insert_subscription(&conn, "broken");
+        insert_subscription(&conn, "ok");
+        conn.execute(
+            "UPDATE subscription SET p256dh = x'0400' WHERE id = ?1",
+            [broken],
+        )
+        .unwrap();
+        let opts = PushOptions::default();
+        insert_message(&mut conn, b"content", &opts, &Target::default()).unwrap();
+        conn.execute("UPDATE message SET urgency = 'urgent'", [])
+            .unwrap();
+        insert_message(&mut conn, b"content", &opts, &Target::default()).unwrap();
+
+        let pending = query_pending(&conn, &KEY, None).unwrap();
+        let names: Vec<_> = pending
+            .iter()
+            .map(|d| match d {
+                Ok(d) => Ok(d.subscription().name()),
+                Err(d) => Err(d.name.as_str()),
+            })
+            .collect();
+        assert_eq!(names, [Err("broken"), Err("ok"), Err("broken"), Ok("ok")]);
+    }
+
+    #[test]
+    fn deliveries_to_removed_subscriptions_are_skipped() {
+        let mut conn = test_db();
+        insert_subscription(&conn, "first");
+        let second = insert_subscription(&conn, "second");
+        insert_message(
+            &mut conn,
+            b"content",
+            &PushOptions::default(),
+            &Target::default(),
+        )
+        .unwrap();
+        conn.execute("DELETE FROM subscription WHERE id = ?1", [second])
+            .unwrap();
+
+        let pending = readable(&conn, None);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].subscription().name(), "first");
+    }
+
+    #[test]
+    fn messages_are_enqueued_for_targets_only() {
+        let mut conn = test_db();
+        insert_subscription(&conn, "first");
+        insert_subscription(&conn, "second");
+        insert_subscription(&conn, "third");
+        let opts = PushOptions {
+            ttl: 60,
+            urgency: Some(Urgency::Low),
+            topic: Some("backups".parse().unwrap()),
+            padding: Some(Padding::PadToBucket),
+        };
+        let target = Target {
+            names: vec![String::from("first"), String::from("third")],
+            topic: None,
+        };
+        let message_id = insert_message(&mut conn, b"content", &opts, &target).unwrap();
+
+        let pending = readable(&conn, Some(message_id));
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].subscription().name(), "first");
+        assert_eq!(pending[1].subscription().name(), "third");
+        assert_eq!(pending[0].options(), &opts);
+    }
+
+    #[test]
+    fn targets_can_be_glob_patterns() {
+        let mut conn = test_db();
+        insert_subscription(&conn, "phone-1");
+        insert_subscription(&conn, "phone-2");
+        insert_subscription(&conn, "kitchen-tablet");
+        insert_subscription(&conn, "Phone-3");
+
+        let target = target_names(&["phone-*"]);
+        let opts = PushOptions::default();
+        let message_id = insert_message(&mut conn, b"content", &opts, &target).unwrap();
+        let pending = readable(&conn, Some(message_id));
+        let names: Vec<_> = pending.iter().map(|d| d.subscription().name()).collect();
+        assert_eq!(names, ["phone-1", "phone-2"]);
+
+        // overlapping patterns do not produce duplicate deliveries
+        let target = target_names(&["*-tablet", "kitchen-*"]);
+        let message_id = insert_message(&mut conn, b"content", &opts, &target).unwrap();
+        let pending = readable(&conn, Some(message_id));
+        assert_eq!(pending.len(), 1);
+
+        let target = target_names(&["nobody"]);
+        let message_id = insert_message(&mut conn, b"content", &opts, &target).unwrap();
+        assert!(readable(&conn, Some(message_id)).is_empty());
+    }
+
+    #[test]
+    fn messages_are_enqueued_for_topic_subscribers() {
+        let mut conn = test_db();
+        let first = insert_subscription(&conn, "phone-1");
+        let second = insert_subscription(&conn, "phone-2");
+        insert_subscription(&conn, "tablet");
+        set_topics(&conn, first, &[String::from("ci")]).unwrap();
+        set_topics(
+            &conn,
+            second,
+            &[String::from("ci"), String::from("backups")],
+        )
+        .unwrap();
+
+        let opts = PushOptions::default();
+        let target = Target {
+            names: vec![],
+            topic: Some(String::from("ci")),
+        };
+        let message_id = insert_message(&mut conn, b"content", &opts, &target).unwrap();
+        let pending = readable(&conn, Some(message_id));
+        let names: Vec<_> = pending.iter().map(|d| d.subscription().name()).collect();
+        assert_eq!(names, ["phone-1", "phone-2"]);
+
+        let target = Target {
+            names: vec![String::from("*-2")],
+            topic: Some(String::from("ci")),
+        };
+        let message_id = insert_message(&mut conn, b"content", &opts, &target).unwrap();
+        let pending = readable(&conn, Some(message_id));
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].subscription().name(), "phone-2");
+
+        // sending to a new topic creates it
+        let target = Target {
+            names: vec![],
+            topic: Some(String::from("home")),
+        };
+        let message_id = insert_message(&mut conn, b"content", &opts, &target).unwrap();
+        assert!(readable(&conn, Some(message_id)).is_empty());
+        let exists: bool = conn
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM topic WHERE name = 'home')",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert!(exists);
+    }
+}
@@ -0,0 +1,156 @@
+use crate::encr::gen_salt;
+use crate::err::Result;
+use crate::utils::parse_var_or;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use std::time::{Duration, SystemTime};
+
+/// When and how many times to retry a push that the push service could not accept right now.
+#[derive(Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+}
+
+impl RetryPolicy {
+    pub fn from_env() -> Result<Self> {
+        let max_attempts = parse_var_or("PUSH_RETRY_ATTEMPTS", 3)?;
+        if max_attempts == 0 {
+            return Err("PUSH_RETRY_ATTEMPTS must be positive".into());
+        }
+        let base_delay = Duration::from_millis(parse_var_or("PUSH_RETRY_BASE_DELAY_MS", 500)?);
+        let max_delay = Duration::from_millis(parse_var_or("PUSH_RETRY_MAX_DELAY_MS", 60_000)?);
+        let jitter = parse_var_or("PUSH_RETRY_JITTER", true)?;
+        Ok(Self {
+            max_attempts,
+            base_delay,
+            max_delay,
+            jitter,
+        })
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// The delay before the next try after `attempt`s (starting from 1) have been made
+    /// or [None] if the push should not be retried. `Retry-After` is honored when the
+    /// push service provides it, unless it exceeds the maximum delay.
+    pub fn delay(&self, attempt: u32, status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+        if attempt >= self.max_attempts || !is_transient(status) {
+            return None;
+        }
+        if let Some(retry_after) = retry_after(headers, SystemTime::now()) {
+            return (retry_after <= self.max_delay).then_some(retry_after);
+        }
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_delay);
+        Some(match self.jitter {
+            true => backoff / 2 + backoff.mul_f64(random_fraction() / 2.0),
+            false => backoff,
+        })
+    }
+}
+
+/// 429 Too Many Requests and 5xx responses are worth retrying
+fn is_transient(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Parse the `Retry-After` header (rfc9110 section 10.2.3), either delay-seconds or a HTTP-date
+fn retry_after(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+/// A random number in [0, 1)
+fn random_fraction() -> f64 {
+    let bytes = gen_salt::<4>().unwrap_or_default();
+    u32::from_be_bytes(bytes) as f64 / (u32::MAX as f64 + 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter: bool) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            jitter,
+        }
+    }
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, retry_after.try_into().unwrap());
+        headers
+    }
+
+    #[test]
+    fn only_transient_errors_are_retried() {
+        let p = policy(false);
+        let h = HeaderMap::new();
+        assert!(p.delay(1, StatusCode::TOO_MANY_REQUESTS, &h).is_some());
+        assert!(p.delay(1, StatusCode::SERVICE_UNAVAILABLE, &h).is_some());
+        assert!(p.delay(1, StatusCode::GONE, &h).is_none());
+        assert!(p.delay(1, StatusCode::CREATED, &h).is_none());
+        assert!(p.delay(4, StatusCode::TOO_MANY_REQUESTS, &h).is_none());
+    }
+
+    #[test]
+    fn backoff_is_exponential_and_capped() {
+        let p = policy(false);
+        let h = HeaderMap::new();
+        let delays: Vec<_> = (1..4)
+            .map(|i| p.delay(i, StatusCode::BAD_GATEWAY, &h).unwrap())
+            .collect();
+        let exp = [100, 200, 400].map(Duration::from_millis);
+        assert_eq!(delays, exp);
+
+        let p = RetryPolicy {
+            max_attempts: 10,
+            ..policy(false)
+        };
+        let d = p.delay(9, StatusCode::BAD_GATEWAY, &h).unwrap();
+        assert_eq!(d, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let p = policy(true);
+        let h = HeaderMap::new();
+        for _ in 0..20 {
+            let d = p.delay(2, StatusCode::BAD_GATEWAY, &h).unwrap();
+            assert!(d >= Duration::from_millis(100) && d <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn retry_after_is_honored() {
+        let p = policy(true);
+        let d = p.delay(1, StatusCode::TOO_MANY_REQUESTS, &headers("1"));
+        assert_eq!(d, Some(Duration::from_secs(1)));
+        let d = p.delay(1, StatusCode::TOO_MANY_REQUESTS, &headers("120"));
+        assert_eq!(d, None);
+    }
+
+    #[test]
+    fn retry_after_parses_http_dates() {
+        let now = httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        let h = headers("Sun, 06 Nov 1994 08:50:07 GMT");
+        assert_eq!(retry_after(&h, now), Some(Duration::from_secs(30)));
+        let h = headers("Sun, 06 Nov 1994 08:49:00 GMT");
+        assert_eq!(retry_after(&h, now), Some(Duration::ZERO));
+        assert_eq!(retry_after(&headers("soon"), now), None);
+    }
+}
@@ -1,19 +1,52 @@
-use crate::base64::base64url_decode;
+use crate::base64::{base64url_decode, base64url_encode};
 use crate::encr::{aes_gcm_decrypt, aes_gcm_encrypt, gen_salt};
 use crate::err::Result;
 use crate::es256::Es256Pub;
+use crate::topic::{set_topics, validate_topic};
 use crate::utils::to_array;
 use crate::{err_other, err_to_resp};
 use axum::extract::{Query, State};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
-use deadpool_sqlite::rusqlite::Connection;
+use deadpool_sqlite::rusqlite::{Connection, OptionalExtension, Row};
 use deadpool_sqlite::Pool;
 use serde::de::Error;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
 use url::Url;
 
+/// Content encoding of the push message payload
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContentEncoding {
+    /// rfc8188 and rfc8291
+    Aes128Gcm,
+    /// The legacy encoding of the earlier drafts, supported by older user agents
+    AesGcm,
+}
+
+impl ContentEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Aes128Gcm => "aes128gcm",
+            ContentEncoding::AesGcm => "aesgcm",
+        }
+    }
+}
+
+impl FromStr for ContentEncoding {
+    type Err = crate::err::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "aes128gcm" => Ok(ContentEncoding::Aes128Gcm),
+            "aesgcm" => Ok(ContentEncoding::AesGcm),
+            _ => Err(format!("unsupported content encoding '{s}'").into()),
+        }
+    }
+}
+
 #[derive(Debug)]
 /// [PushSubscription](https://developer.mozilla.org/en-US/docs/Web/API/PushSubscription) returned
 /// by the browser with the addition of `name`-field for subscription name.
@@ -23,6 +56,8 @@ pub struct Subscription {
     expiration_time: Option<u32>,
     auth: [u8; 16],
     p256dh: Es256Pub,
+    /// The supported encodings known to us, aes128gcm is assumed if empty
+    content_encodings: Vec<ContentEncoding>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -30,6 +65,21 @@ pub struct Endpoint {
     endpoint: Url,
 }
 
+impl Endpoint {
+    pub fn endpoint(&self) -> &Url {
+        &self.endpoint
+    }
+}
+
+/// Body of the subscribe request: the [Subscription] and the topics to subscribe to.
+#[derive(Debug, Deserialize)]
+pub struct SubscribeRequest {
+    #[serde(flatten)]
+    subscription: Subscription,
+    #[serde(default)]
+    topics: Vec<String>,
+}
+
 impl<'de> Deserialize<'de> for Subscription {
     fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
     where
@@ -47,8 +97,19 @@ impl<'de> Deserialize<'de> for Subscription {
             #[serde(rename = "expirationTime")]
             expiration_time: Option<u32>,
             keys: SubscriptionKeysRaw,
+            /// `PushManager.supportedContentEncodings`
+            #[serde(rename = "contentEncodings", default)]
+            content_encodings: Vec<String>,
         }
         let raw = SubscriptionRaw::deserialize(deserializer)?;
+        let content_encodings: Vec<_> = raw
+            .content_encodings
+            .iter()
+            .filter_map(|e| e.parse().ok())
+            .collect();
+        if content_encodings.is_empty() && !raw.content_encodings.is_empty() {
+            return Err(D::Error::custom("no supported content encoding"));
+        }
         let auth = base64url_decode(raw.keys.auth).and_then(to_array);
         let p256dh =
             base64url_decode(raw.keys.p256dh).and_then(|k| Es256Pub::try_from(k.as_slice()));
@@ -58,10 +119,47 @@ impl<'de> Deserialize<'de> for Subscription {
             expiration_time: raw.expiration_time,
             auth: auth.map_err(D::Error::custom)?,
             p256dh: p256dh.map_err(D::Error::custom)?,
+            content_encodings,
         })
     }
 }
 
+impl Serialize for Subscription {
+    /// The inverse of deserializing, with the keys in plain text
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        #[derive(Serialize)]
+        struct SubscriptionKeysRaw {
+            auth: String,
+            p256dh: String,
+        }
+        #[derive(Serialize)]
+        struct SubscriptionRaw<'a> {
+            endpoint: &'a Url,
+            name: &'a str,
+            #[serde(rename = "expirationTime")]
+            expiration_time: Option<u32>,
+            keys: SubscriptionKeysRaw,
+            #[serde(rename = "contentEncodings")]
+            content_encodings: Vec<&'static str>,
+        }
+        let p256dh = Vec::try_from(&self.p256dh).map_err(serde::ser::Error::custom)?;
+        let raw = SubscriptionRaw {
+            endpoint: &self.endpoint,
+            name: &self.name,
+            expiration_time: self.expiration_time,
+            keys: SubscriptionKeysRaw {
+                auth: base64url_encode(self.auth),
+                p256dh: base64url_encode(p256dh),
+            },
+            content_encodings: self.content_encodings.iter().map(|e| e.as_str()).collect(),
+        };
+        raw.serialize(serializer)
+    }
+}
+
 impl Subscription {
     /// Encrypts the `auth`-field using AES-128 in GCM.
     /// Returns the generated salt, encrypted auth and the encryption tag.
@@ -91,33 +189,179 @@ impl Subscription {
         &self.p256dh
     }
 
+    /// The encoding to encrypt the push messages with, aes128gcm if supported
+    pub fn content_encoding(&self) -> ContentEncoding {
+        match self.content_encodings.as_slice() {
+            [] => ContentEncoding::Aes128Gcm,
+            encodings if encodings.contains(&ContentEncoding::Aes128Gcm) => {
+                ContentEncoding::Aes128Gcm
+            }
+            [encoding, ..] => *encoding,
+        }
+    }
+
+    /// Columns that [Subscription::from_row] expects, in order.
+    pub(crate) const COLUMNS: &'static str =
+        "endpoint, name, expiration_time, auth_encr, salt, tag, p256dh, content_encodings";
+
+    /// Read a subscription from a row starting with [Subscription::COLUMNS],
+    /// decrypting the `auth`-field with `key`.
+    pub(crate) fn from_row(r: &Row, key: &[u8; 16]) -> Result<Self> {
+        let auth_decr = aes_gcm_decrypt(&r.get::<_, Vec<_>>(3)?, key, &r.get(4)?, &r.get(5)?)?;
+        Ok(Self {
+            endpoint: err_other!(Url::parse(&r.get::<_, String>(0)?))?,
+            name: r.get(1)?,
+            expiration_time: r.get(2)?,
+            auth: to_array(auth_decr)?,
+            p256dh: Es256Pub::try_from(r.get::<_, Vec<_>>(6)?.as_slice())?,
+            content_encodings: r
+                .get::<_, Option<String>>(7)?
+                .map(|e| e.split(',').map(str::parse).collect())
+                .transpose()?
+                .unwrap_or_default(),
+        })
+    }
+
     fn query(conn: &Connection, key: [u8; 16]) -> Result<Vec<Self>> {
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM subscription", Self::COLUMNS))?;
+        let mut rows = stmt.query([])?;
+        let mut v = vec![];
+        while let Some(r) = rows.next()? {
+            v.push(Self::from_row(r, &key)?);
+        }
+        Ok(v)
+    }
+
+    fn query_matching(
+        conn: &Connection,
+        key: [u8; 16],
+        sub: &SubscriptionRef,
+    ) -> Result<Vec<Self>> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM subscription WHERE id = ?1 OR name = ?2",
+            Self::COLUMNS
+        ))?;
+        let mut rows = stmt.query(sub.params())?;
+        let mut v = vec![];
+        while let Some(r) = rows.next()? {
+            v.push(Self::from_row(r, &key)?);
+        }
+        Ok(v)
+    }
+}
+
+/// Refers to subscriptions by id or by name, strings of digits are taken as ids
+#[derive(Clone, Debug, PartialEq)]
+pub enum SubscriptionRef {
+    Id(u32),
+    Name(String),
+}
+
+impl SubscriptionRef {
+    /// Parameters for `WHERE id = ?1 OR name = ?2`
+    fn params(&self) -> (Option<u32>, Option<&str>) {
+        match self {
+            SubscriptionRef::Id(id) => (Some(*id), None),
+            SubscriptionRef::Name(name) => (None, Some(name)),
+        }
+    }
+}
+
+impl fmt::Display for SubscriptionRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubscriptionRef::Id(id) => write!(f, "id {id}"),
+            SubscriptionRef::Name(name) => write!(f, "name '{name}'"),
+        }
+    }
+}
+
+impl FromStr for SubscriptionRef {
+    type Err = crate::err::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.parse() {
+            _ if s.is_empty() => Err("empty subscription name".into()),
+            Ok(id) => Ok(SubscriptionRef::Id(id)),
+            Err(_) => Ok(SubscriptionRef::Name(s.to_string())),
+        }
+    }
+}
+
+/// A subscription without its keys, for listing the subscriptions
+#[derive(Debug, PartialEq, Serialize)]
+pub struct SubscriptionInfo {
+    pub id: u32,
+    pub name: String,
+    /// The host of the push service, the endpoint itself is not shown
+    pub endpoint_host: String,
+    pub inserted: String,
+    pub expiration_time: Option<u32>,
+}
+
+impl SubscriptionInfo {
+    fn query(conn: &Connection) -> Result<Vec<Self>> {
         let mut stmt = conn.prepare(
-            "SELECT endpoint, name, expiration_time, auth_encr, salt, tag, p256dh FROM subscription",
+            "SELECT id, name, endpoint, inserted, expiration_time FROM subscription ORDER BY id",
         )?;
         let mut rows = stmt.query([])?;
         let mut v = vec![];
         while let Some(r) = rows.next()? {
-            let auth_decr = aes_gcm_decrypt(&r.get::<_, Vec<_>>(3)?, &key, &r.get(4)?, &r.get(5)?)?;
+            let endpoint = err_other!(Url::parse(&r.get::<_, String>(2)?))?;
             v.push(Self {
-                endpoint: err_other!(Url::parse(&r.get::<_, String>(0)?))?,
+                id: r.get(0)?,
                 name: r.get(1)?,
-                expiration_time: r.get(2)?,
-                auth: to_array(auth_decr)?,
-                p256dh: Es256Pub::try_from(r.get::<_, Vec<_>>(6)?.as_slice())?,
+                endpoint_host: endpoint.host_str().unwrap_or_default().to_string(),
+                inserted: r.get(3)?,
+                expiration_time: r.get(4)?,
             });
         }
         Ok(v)
     }
 }
 
+fn rename(conn: &Connection, sub: &SubscriptionRef, name: &str) -> Result<usize> {
+    if name.is_empty() {
+        return Err("empty subscription name".into());
+    }
+    let (id, old_name) = sub.params();
+    Ok(conn.execute(
+        "UPDATE subscription SET name = ?3 WHERE id = ?1 OR name = ?2",
+        (id, old_name, name),
+    )?)
+}
+
+fn delete_endpoint(conn: &Connection, endpoint: &str) -> Result<Option<u32>> {
+    Ok(conn
+        .query_row(
+            "DELETE FROM subscription WHERE endpoint = (?1) RETURNING id",
+            [endpoint],
+            |r| r.get(0),
+        )
+        .optional()?)
+}
+
+fn delete(conn: &Connection, sub: &SubscriptionRef) -> Result<usize> {
+    Ok(conn.execute(
+        "DELETE FROM subscription WHERE id = ?1 OR name = ?2",
+        sub.params(),
+    )?)
+}
+
 /// Insert a new subscription to the database
 pub async fn subscribe(
     State((pool, encryption_key)): State<(Pool, [u8; 16])>,
-    Json(sub): Json<Subscription>,
+    Json(req): Json<SubscribeRequest>,
 ) -> Response {
-    tracing::info!("SUBSCRIBE {}", sub.endpoint());
-    err_to_resp!(insert_subscription(pool, &encryption_key, &sub).await);
+    let SubscribeRequest {
+        subscription,
+        topics,
+    } = req;
+    tracing::info!("SUBSCRIBE {} {:?}", subscription.endpoint(), topics);
+    if let Err(e) = topics.iter().try_for_each(|t| validate_topic(t)) {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+    err_to_resp!(insert_subscription(pool, &encryption_key, &subscription, topics).await);
     StatusCode::OK.into_response()
 }
 
@@ -127,43 +371,55 @@ pub async fn unsubscribe(
     Query(query): Query<Endpoint>,
 ) -> Response {
     tracing::info!("UNSUBSCRIBE {}", query.endpoint);
-    err_to_resp!(delete_subscription(pool, &query.endpoint).await);
+    err_to_resp!(delete_subscription(&pool, &query.endpoint).await);
     StatusCode::OK.into_response()
 }
 
-async fn delete_subscription(pool: Pool, endpoint: &Url) -> Result<u32> {
+/// Delete the subscription with the given `endpoint` (along with its topics and deliveries)
+/// from the database, returning its id or `None` if there is no such subscription.
+pub async fn delete_subscription(pool: &Pool, endpoint: &Url) -> Result<Option<u32>> {
     let conn = pool.get().await?;
     let ep = endpoint.to_string();
-    conn.interact(move |c| {
-        Ok(c.query_row(
-            "DELETE FROM subscription WHERE endpoint = (?1) RETURNING id",
-            [ep],
-            |r| r.get(0),
-        )?)
-    })
-    .await?
+    conn.interact(move |c| delete_endpoint(c, &ep)).await?
 }
 
 async fn insert_subscription(
     pool: Pool,
     encryption_key: &[u8; 16],
     sub: &Subscription,
+    topics: Vec<String>,
 ) -> Result<u32> {
     let (salt, auth_encr, tag) = sub.encrypted_auth(encryption_key)?;
     let p256dh = Vec::try_from(&sub.p256dh)?;
     let endpoint = sub.endpoint.to_string();
     let name = sub.name.clone();
     let expr = sub.expiration_time;
+    let encodings = match sub.content_encodings.as_slice() {
+        [] => None,
+        encodings => Some(
+            encodings
+                .iter()
+                .map(|e| e.as_str())
+                .collect::<Vec<_>>()
+                .join(","),
+        ),
+    };
     let conn = pool.get().await?;
     conn.interact(move |c| {
-        Ok(c.query_row(
+        let tx = c.transaction()?;
+        let id = tx.query_row(
             "INSERT INTO subscription
-            (endpoint, name, expiration_time, auth_encr, tag, salt, p256dh)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            (endpoint, name, expiration_time, auth_encr, tag, salt, p256dh, content_encodings)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
             RETURNING id",
-            (endpoint, name, expr, auth_encr, tag, salt, p256dh),
+            (
+                endpoint, name, expr, auth_encr, tag, salt, p256dh, encodings,
+            ),
             |r| r.get(0),
-        )?)
+        )?;
+        set_topics(&tx, id, &topics)?;
+        tx.commit()?;
+        Ok(id)
     })
     .await?
 }
@@ -173,3 +429,163 @@ pub async fn get_subscriptions(pool: &Pool, key: [u8; 16]) -> Result<Vec<Subscri
     let conn = pool.get().await?;
     conn.interact(move |c| Subscription::query(c, key)).await?
 }
+
+/// Query for the [Subscription]s matching `sub`.
+pub async fn find_subscriptions(
+    pool: &Pool,
+    key: [u8; 16],
+    sub: SubscriptionRef,
+) -> Result<Vec<Subscription>> {
+    let conn = pool.get().await?;
+    conn.interact(move |c| Subscription::query_matching(c, key, &sub))
+        .await?
+}
+
+/// List the subscriptions without decrypting them
+pub async fn list_subscriptions(pool: &Pool) -> Result<Vec<SubscriptionInfo>> {
+    let conn = pool.get().await?;
+    conn.interact(|c| SubscriptionInfo::query(c)).await?
+}
+
+/// Rename the matching subscriptions, returning the number of subscriptions renamed
+pub async fn rename_subscription(pool: &Pool, sub: SubscriptionRef, name: String) -> Result<usize> {
+    let conn = pool.get().await?;
+    conn.interact(move |c| rename(c, &sub, &name)).await?
+}
+
+/// Delete the matching subscriptions along with their topics and deliveries, returning the
+/// number of subscriptions deleted
+pub async fn delete_subscriptions(pool: &Pool, sub: SubscriptionRef) -> Result<usize> {
+    let conn = pool.get().await?;
+    conn.interact(move |c| delete(c, &sub)).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(content_encodings: &str) -> serde_json::Result<Subscription> {
+        // keys from https://www.rfc-editor.org/rfc/rfc8291#appendix-A
+        serde_json::from_str(&format!(
+            r#"{{
+                "endpoint": "https://push.example.net/push/1",
+                "name": "kiosk",
+                "expirationTime": null,
+                "keys": {{
+                    "auth": "BTBZMqHH6r4Tts7J_aSIgg",
+                    "p256dh": "BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4"
+                }}
+                {content_encodings}
+            }}"#
+        ))
+    }
+
+    #[test]
+    fn content_encoding_is_chosen() {
+        let sub = subscription("").unwrap();
+        assert_eq!(sub.content_encoding(), ContentEncoding::Aes128Gcm);
+        let sub = subscription(r#","contentEncodings":["aesgcm","aes128gcm"]"#).unwrap();
+        assert_eq!(sub.content_encoding(), ContentEncoding::Aes128Gcm);
+        let sub = subscription(r#","contentEncodings":["aesgcm","future"]"#).unwrap();
+        assert_eq!(sub.content_encoding(), ContentEncoding::AesGcm);
+        assert!(subscription(r#","contentEncodings":["future"]"#).is_err());
+    }
+
+    fn test_db() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "foreign_keys", "ON").unwrap();
+        crate::db::migrate(&mut conn).unwrap();
+        for (endpoint, name) in [
+            ("https://push.example.net/push/1", "kiosk"),
+            ("https://updates.push.test/2", "phone"),
+            ("https://updates.push.test/3", "phone"),
+            ("https://push.example.net/push/4", "12"),
+        ] {
+            conn.execute(
+                "INSERT INTO subscription (endpoint, name, auth_encr, salt, tag, p256dh)
+                VALUES (?1, ?2, x'00', x'00', x'00', x'00')",
+                (endpoint, name),
+            )
+            .unwrap();
+        }
+        set_topics(&conn, 2, &[String::from("backups")]).unwrap();
+        conn.execute_batch(
+            "INSERT INTO message (id, content, ttl) VALUES (1, x'00', 60);
+            INSERT INTO delivery (message_id, subscription_id) VALUES (1, 1), (1, 2), (1, 3);",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn count(conn: &Connection, table: &str) -> u32 {
+        let sql = format!("SELECT COUNT(*) FROM {table}");
+        conn.query_row(&sql, [], |r| r.get(0)).unwrap()
+    }
+
+    fn names(conn: &Connection) -> Vec<String> {
+        let subs = SubscriptionInfo::query(conn).unwrap();
+        subs.into_iter().map(|s| s.name).collect()
+    }
+
+    #[test]
+    fn subscriptions_are_referred_by_id_or_name() {
+        assert_eq!(
+            "12".parse::<SubscriptionRef>().unwrap(),
+            SubscriptionRef::Id(12)
+        );
+        assert_eq!(
+            "phone".parse::<SubscriptionRef>().unwrap(),
+            SubscriptionRef::Name(String::from("phone"))
+        );
+        assert!("".parse::<SubscriptionRef>().is_err());
+    }
+
+    #[test]
+    fn subscriptions_are_listed() {
+        let conn = test_db();
+        let subs = SubscriptionInfo::query(&conn).unwrap();
+        assert_eq!(subs.len(), 4);
+        assert_eq!(subs[1].id, 2);
+        assert_eq!(subs[1].name, "phone");
+        assert_eq!(subs[1].endpoint_host, "updates.push.test");
+        assert_eq!(subs[1].expiration_time, None);
+    }
+
+    #[test]
+    fn subscriptions_are_renamed_and_deleted() {
+        let conn = test_db();
+        let phone = SubscriptionRef::Name(String::from("phone"));
+        assert_eq!(rename(&conn, &SubscriptionRef::Id(1), "tablet").unwrap(), 1);
+        assert_eq!(rename(&conn, &SubscriptionRef::Id(9), "tablet").unwrap(), 0);
+        assert!(rename(&conn, &SubscriptionRef::Id(1), "").is_err());
+        assert_eq!(names(&conn), ["tablet", "phone", "phone", "12"]);
+
+        assert_eq!(delete(&conn, &phone).unwrap(), 2);
+        assert_eq!(delete(&conn, &SubscriptionRef::Id(12)).unwrap(), 0);
+        assert_eq!(delete(&conn, &SubscriptionRef::Id(4)).unwrap(), 1);
+        assert_eq!(names(&conn), ["tablet"]);
+        assert_eq!(count(&conn, "subscription_topic"), 0);
+        assert_eq!(count(&conn, "delivery"), 1);
+    }
+
+    #[test]
+    fn subscriptions_are_deleted_by_endpoint() {
+        let conn = test_db();
+        let endpoint = "https://updates.push.test/2";
+        assert_eq!(delete_endpoint(&conn, endpoint).unwrap(), Some(2));
+        // e.g. two deliveries to the same endpoint were both rejected with 410
+        assert_eq!(delete_endpoint(&conn, endpoint).unwrap(), None);
+        assert_eq!(names(&conn), ["kiosk", "phone", "12"]);
+        assert_eq!(count(&conn, "delivery"), 2);
+    }
+
+    #[test]
+    fn subscription_is_serialized_like_deserialized() {
+        let sub = subscription(r#","contentEncodings":["aesgcm"]"#).unwrap();
+        let json = serde_json::to_value(&sub).unwrap();
+        assert_eq!(json["keys"]["auth"], "BTBZMqHH6r4Tts7J_aSIgg");
+        assert_eq!(json["contentEncodings"], serde_json::json!(["aesgcm"]));
+        let roundtrip: Subscription = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&roundtrip).unwrap(), json);
+    }
+}
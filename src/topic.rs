@@ -0,0 +1,207 @@
+use crate::err::Result;
+use crate::err_to_resp;
+use crate::subscription::Endpoint;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use deadpool_sqlite::rusqlite::{Connection, OptionalExtension};
+use deadpool_sqlite::Pool;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+const MAX_TOPIC_LEN: usize = 64;
+
+#[derive(Debug, Serialize)]
+pub struct Topics {
+    topics: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TopicsUpdate {
+    endpoint: Url,
+    topics: Vec<String>,
+}
+
+/// Topic names must be non-empty and at most [MAX_TOPIC_LEN] characters long.
+pub fn validate_topic(topic: &str) -> Result<()> {
+    match topic.trim().is_empty() || topic.chars().count() > MAX_TOPIC_LEN {
+        true => Err(format!("invalid topic '{topic}'").into()),
+        false => Ok(()),
+    }
+}
+
+/// Create the topics that do not exist yet.
+pub(crate) fn insert_topics(conn: &Connection, topics: &[String]) -> Result<()> {
+    let mut stmt = conn.prepare("INSERT OR IGNORE INTO topic (name) VALUES (?1)")?;
+    for topic in topics {
+        validate_topic(topic)?;
+        stmt.execute([topic])?;
+    }
+    Ok(())
+}
+
+/// Replace the topics of the subscription, creating the topics if needed.
+pub(crate) fn set_topics(conn: &Connection, subscription_id: u32, topics: &[String]) -> Result<()> {
+    insert_topics(conn, topics)?;
+    conn.execute(
+        "DELETE FROM subscription_topic WHERE subscription_id = ?1",
+        [subscription_id],
+    )?;
+    let mut stmt = conn.prepare(
+        "INSERT OR IGNORE INTO subscription_topic (subscription_id, topic_id)
+        SELECT ?1, id FROM topic WHERE name = ?2",
+    )?;
+    for topic in topics {
+        stmt.execute((subscription_id, topic))?;
+    }
+    Ok(())
+}
+
+fn query_topics(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT name FROM topic ORDER BY name")?;
+    let topics = stmt.query_map([], |r| r.get(0))?;
+    Ok(topics.collect::<std::result::Result<_, _>>()?)
+}
+
+fn query_subscription_topics(conn: &Connection, endpoint: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT t.name FROM topic t
+        JOIN subscription_topic st ON st.topic_id = t.id
+        JOIN subscription s ON s.id = st.subscription_id
+        WHERE s.endpoint = ?1
+        ORDER BY t.name",
+    )?;
+    let topics = stmt.query_map([endpoint], |r| r.get(0))?;
+    Ok(topics.collect::<std::result::Result<_, _>>()?)
+}
+
+fn update_subscription_topics(
+    conn: &mut Connection,
+    endpoint: &str,
+    topics: &[String],
+) -> Result<bool> {
+    let tx = conn.transaction()?;
+    let id = tx
+        .query_row(
+            "SELECT id FROM subscription WHERE endpoint = ?1",
+            [endpoint],
+            |r| r.get(0),
+        )
+        .optional()?;
+    let Some(id) = id else {
+        return Ok(false);
+    };
+    set_topics(&tx, id, topics)?;
+    tx.commit()?;
+    Ok(true)
+}
+
+/// Query for the names of all the topics.
+pub async fn get_topics(pool: &Pool) -> Result<Vec<String>> {
+    let conn = pool.get().await?;
+    conn.interact(|c| query_topics(c)).await?
+}
+
+/// Query for the topics of the subscription with the given `endpoint`.
+pub async fn get_subscription_topics(pool: &Pool, endpoint: &Url) -> Result<Vec<String>> {
+    let conn = pool.get().await?;
+    let ep = endpoint.to_string();
+    conn.interact(move |c| query_subscription_topics(c, &ep))
+        .await?
+}
+
+/// Replace the topics of the subscription with the given `endpoint`.
+/// Returns `false` if the subscription does not exist.
+pub async fn set_subscription_topics(
+    pool: &Pool,
+    endpoint: &Url,
+    topics: Vec<String>,
+) -> Result<bool> {
+    let conn = pool.get().await?;
+    let ep = endpoint.to_string();
+    conn.interact(move |c| update_subscription_topics(c, &ep, &topics))
+        .await?
+}
+
+/// List all the topics
+pub async fn list_topics(State((pool, _)): State<(Pool, [u8; 16])>) -> Response {
+    let topics = err_to_resp!(get_topics(&pool).await);
+    (StatusCode::OK, Json(Topics { topics })).into_response()
+}
+
+/// List the topics of the subscription
+pub async fn subscription_topics(
+    State((pool, _)): State<(Pool, [u8; 16])>,
+    Query(query): Query<Endpoint>,
+) -> Response {
+    let topics = err_to_resp!(get_subscription_topics(&pool, query.endpoint()).await);
+    (StatusCode::OK, Json(Topics { topics })).into_response()
+}
+
+/// Replace the topics of the subscription
+pub async fn update_topics(
+    State((pool, _)): State<(Pool, [u8; 16])>,
+    Json(update): Json<TopicsUpdate>,
+) -> Response {
+    tracing::info!("TOPICS {} {:?}", update.endpoint, update.topics);
+    if let Err(e) = update.topics.iter().try_for_each(|t| validate_topic(t)) {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+    match err_to_resp!(set_subscription_topics(&pool, &update.endpoint, update.topics).await) {
+        true => StatusCode::OK.into_response(),
+        false => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        for migration in [
+            include_str!("../migrations/002_subscriptions_name.sql"),
+            include_str!("../migrations/005_topics.sql"),
+        ] {
+            let migration = migration.replace("DROP TABLE subscription;", "");
+            conn.execute_batch(&migration).unwrap();
+        }
+        conn.execute(
+            "INSERT INTO subscription (id, endpoint, name, auth_encr, salt, tag, p256dh)
+            VALUES (1, 'https://push.example.net/1', 'first', x'', x'', x'', x'')",
+            [],
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn validate_topic_works() {
+        assert!(validate_topic("backups").is_ok());
+        assert!(validate_topic("").is_err());
+        assert!(validate_topic("  ").is_err());
+        assert!(validate_topic(&"x".repeat(MAX_TOPIC_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn subscription_topics_are_replaced() {
+        let mut conn = test_db();
+        let endpoint = "https://push.example.net/1";
+        let topics = [String::from("ci"), String::from("backups")];
+        assert!(update_subscription_topics(&mut conn, endpoint, &topics).unwrap());
+        let topics = query_subscription_topics(&conn, endpoint).unwrap();
+        assert_eq!(topics, ["backups", "ci"]);
+
+        let topics = [String::from("home")];
+        assert!(update_subscription_topics(&mut conn, endpoint, &topics).unwrap());
+        assert_eq!(
+            query_subscription_topics(&conn, endpoint).unwrap(),
+            ["home"]
+        );
+        assert_eq!(query_topics(&conn).unwrap(), ["backups", "ci", "home"]);
+
+        let missing = "https://push.example.net/2";
+        assert!(!update_subscription_topics(&mut conn, missing, &topics).unwrap());
+    }
+}
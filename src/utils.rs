@@ -1,6 +1,7 @@
 use crate::err::Result;
 use crate::err_other;
 use std::env::var;
+use std::str::FromStr;
 
 /// Transform slice into array of size `N`, discarding all the extra elements.
 pub fn to_array<const N: usize, V: AsRef<[u8]>>(slice: V) -> Result<[u8; N]> {
@@ -14,3 +15,14 @@ pub fn get_var(var_name: &str) -> Result<String> {
         "environment variable '{var_name}' missing"
     )?)
 }
+
+/// Parse an optional environment variable, returning `default` if it does not exist
+pub fn parse_var_or<T: FromStr>(var_name: &str, default: T) -> Result<T> {
+    match var(var_name) {
+        Ok(v) => Ok(err_other!(
+            v.parse(),
+            "invalid environment variable '{var_name}'"
+        )?),
+        Err(_) => Ok(default),
+    }
+}
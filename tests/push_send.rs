@@ -0,0 +1,425 @@
+//! Runs `push-send` and `push-admin` against [MockPushService].
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use deadpool_sqlite::Pool;
+use pusher::base64::base64url_encode;
+use pusher::db::{get_pool, run_migrations};
+use pusher::es256::Es256;
+use pusher::subscription::{get_subscriptions, subscribe};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::{env, fs};
+use support::MockPushService;
+
+mod support;
+
+/// A database with subscriptions to the mock push service
+struct Setup {
+    dir: PathBuf,
+    pool: Pool,
+    encryption_key: [u8; 16],
+    vapid: Es256,
+    mock: MockPushService,
+}
+
+impl Setup {
+    async fn new(test: &str) -> Self {
+        let dir = env::temp_dir().join(format!("pusher-{test}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("subscriptions.db");
+        let _ = fs::remove_file(&db_path);
+        let pool = get_pool(db_path.to_str().unwrap(), false).unwrap();
+        run_migrations(&pool).await.unwrap();
+        Self {
+            pool,
+            dir,
+            encryption_key: *b"database enc key",
+            vapid: Es256::gen().unwrap(),
+            mock: MockPushService::start().await,
+        }
+    }
+
+    async fn subscribe(&self, name: &str, content_encodings: &[&str]) {
+        let sub = self.mock.subscription(name, content_encodings);
+        let state = State((self.pool.clone(), self.encryption_key));
+        let resp = subscribe(state, Json(serde_json::from_value(sub).unwrap())).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    async fn subscription_names(&self) -> Vec<String> {
+        let subs = get_subscriptions(&self.pool, self.encryption_key).await;
+        let mut names: Vec<_> = subs.unwrap().iter().map(|s| s.name().to_string()).collect();
+        names.sort();
+        names
+    }
+
+    async fn push_send(&self, args: &[&str], input: &str) -> Output {
+        self.run(env!("CARGO_BIN_EXE_push-send"), args, input).await
+    }
+
+    async fn push_admin(&self, args: &[&str]) -> Output {
+        self.run(env!("CARGO_BIN_EXE_push-admin"), args, "").await
+    }
+
+    /// Run the binary at `path` with `input` as the standard input
+    async fn run(&self, path: &str, args: &[&str], input: &str) -> Output {
+        let mut cmd = Command::new(path);
+        cmd.args(args)
+            .env_clear()
+            .env(
+                "VAPID_PUBLIC_KEY",
+                base64url_encode(self.vapid.public_key().unwrap()),
+            )
+            .env(
+                "VAPID_PRIVATE_KEY",
+                base64url_encode(self.vapid.private_key()),
+            )
+            .env("VAPID_SUBJECT", "mailto:test@example.com")
+            .env(
+                "DATABASE_ENCRYPTION_KEY",
+                base64url_encode(self.encryption_key),
+            )
+            .env("DATABASE_PATH", self.dir.join("subscriptions.db"))
+            .env("PUSH_SOCKET_ADDR", self.dir.join("push-test-socket"))
+            .env("PUSH_RETRY_BASE_DELAY_MS", "1")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let input = input.to_string();
+        tokio::task::spawn_blocking(move || {
+            let mut child = cmd.spawn().unwrap();
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(input.as_bytes())
+                .unwrap();
+            child.wait_with_output().unwrap()
+        })
+        .await
+        .unwrap()
+    }
+}
+
+impl Drop for Setup {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn assert_success(output: &Output) {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "failed: {stderr}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn messages_are_delivered_encrypted() {
+    let setup = Setup::new("delivered").await;
+    setup.subscribe("phone", &["aes128gcm", "aesgcm"]).await;
+    setup.subscribe("old-phone", &["aesgcm"]).await;
+
+    let args = [
+        "--ttl",
+        "60",
+        "--urgency",
+        "high",
+        "--replace-topic",
+        "status",
+        "--padding",
+        "pad-to-bucket",
+        "greeting",
+    ];
+    assert_success(&setup.push_send(&args, "hello").await);
+
+    let mut received = setup.mock.received();
+    received.sort_by(|a, b| a.name.cmp(&b.name));
+    assert_eq!(received.len(), 2);
+    for (push, encoding) in received.iter().zip(["aesgcm", "aes128gcm"]) {
+        assert_eq!(push.status, StatusCode::CREATED);
+        assert_eq!(push.header("Content-Encoding"), Some(encoding));
+        assert_eq!(push.header("TTL"), Some("60"));
+        assert_eq!(push.header("Urgency"), Some("high"));
+        assert_eq!(push.header("Topic"), Some("status"));
+        let msg = push.json();
+        assert_eq!(msg["title"], "greeting");
+        assert_eq!(msg["options"]["body"], "hello");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn messages_are_targeted() {
+    let setup = Setup::new("targeted").await;
+    for name in ["phone-1", "phone-2", "laptop"] {
+        setup.subscribe(name, &[]).await;
+    }
+
+    assert_success(&setup.push_send(&["--to", "phone-*", "t"], "body").await);
+
+    let mut names: Vec<_> = setup.mock.received().into_iter().map(|r| r.name).collect();
+    names.sort();
+    assert_eq!(names, ["phone-1", "phone-2"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn expired_subscriptions_are_pruned() {
+    let setup = Setup::new("pruned").await;
+    for name in ["gone", "not-found", "too-large", "ok"] {
+        setup.subscribe(name, &[]).await;
+    }
+    setup.mock.respond_with("gone", &[StatusCode::GONE]);
+    setup
+        .mock
+        .respond_with("not-found", &[StatusCode::NOT_FOUND]);
+    setup
+        .mock
+        .respond_with("too-large", &[StatusCode::PAYLOAD_TOO_LARGE]);
+
+    assert_success(&setup.push_send(&["t"], "body").await);
+
+    assert_eq!(setup.mock.received().len(), 4);
+    assert_eq!(setup.subscription_names().await, ["ok", "too-large"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn subscriptions_to_the_same_endpoint_are_pruned_together() {
+    let setup = Setup::new("pruned-together").await;
+    setup.subscribe("tablet", &[]).await;
+    setup.subscribe("tablet", &[]).await;
+    setup
+        .mock
+        .respond_with("tablet", &[StatusCode::GONE, StatusCode::GONE]);
+
+    let output = setup.push_send(&["t"], "body").await;
+    assert_success(&output);
+
+    // the first removal of the endpoint removes both subscriptions
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("0 delivered, 2 rejected, 0 failed, 2 pruned"),
+        "{stdout}"
+    );
+    assert!(setup.subscription_names().await.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rate_limited_pushes_are_retried() {
+    let setup = Setup::new("retried").await;
+    setup.subscribe("busy", &[]).await;
+    setup.subscribe("overloaded", &[]).await;
+    let too_many = StatusCode::TOO_MANY_REQUESTS;
+    setup.mock.respond_with("busy", &[too_many, too_many]);
+    setup
+        .mock
+        .respond_with("overloaded", &[too_many, too_many, too_many]);
+
+    assert_success(&setup.push_send(&["t"], "body").await);
+
+    let statuses = |name| -> Vec<_> {
+        let received = setup.mock.received().into_iter();
+        received
+            .filter(|r| r.name == name)
+            .map(|r| r.status)
+            .collect()
+    };
+    assert_eq!(statuses("busy"), [too_many, too_many, StatusCode::CREATED]);
+    assert_eq!(statuses("overloaded"), [too_many, too_many, too_many]);
+    assert_eq!(setup.subscription_names().await, ["busy", "overloaded"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn too_large_messages_are_not_sent() {
+    let setup = Setup::new("too-large").await;
+    setup.subscribe("phone", &[]).await;
+
+    let output = setup.push_send(&["t"], &"a".repeat(5000)).await;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("payload too large"));
+
+    assert_success(
+        &setup
+            .push_send(&["--truncate", "t"], &"a".repeat(5000))
+            .await,
+    );
+    let received = setup.mock.received();
+    assert_eq!(received.len(), 1);
+    assert!(received[0].json()["options"]["body"]
+        .as_str()
+        .unwrap()
+        .ends_with('…'));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_push_shows_the_response() {
+    let setup = Setup::new("test-push").await;
+    setup.subscribe("phone", &[]).await;
+    setup.subscribe("tablet", &[]).await;
+    setup.subscribe("tablet", &[]).await;
+    setup.mock.respond_with("phone", &[StatusCode::GONE]);
+
+    let output = setup.push_admin(&["test-push", "phone"]).await;
+    assert_success(&output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("410 Gone"), "{stdout}");
+    let output = setup.push_admin(&["test-push", "1"]).await;
+    assert_success(&output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("201 Created"), "{stdout}");
+    assert!(stdout.contains("location: http://"), "{stdout}");
+
+    let received = setup.mock.received();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[1].json()["options"]["body"], "Test push to phone");
+    // diagnostics do not prune the subscriptions
+    assert_eq!(
+        setup.subscription_names().await,
+        ["phone", "tablet", "tablet"]
+    );
+
+    assert!(!setup
+        .push_admin(&["test-push", "tablet"])
+        .await
+        .status
+        .success());
+    assert!(!setup
+        .push_admin(&["test-push", "laptop"])
+        .await
+        .status
+        .success());
+    assert_success(&setup.push_admin(&["test-push", "3"]).await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn interrupted_deliveries_are_resumed_within_the_attempts() {
+    let setup = Setup::new("resumed").await;
+    setup.subscribe("phone", &[]).await;
+    setup.subscribe("laptop", &[]).await;
+    // as if an earlier push-send had been interrupted while retrying, 3 attempts at most
+    let conn = setup.pool.get().await.unwrap();
+    conn.interact(|c| {
+        c.execute_batch(
+            "INSERT INTO message (id, content, ttl) VALUES (100, x'6f6c64', 60); -- 'old'
+            INSERT INTO delivery (message_id, subscription_id, attempts)
+            VALUES (100, 1, 2), (100, 2, 3);",
+        )
+    })
+    .await
+    .unwrap()
+    .unwrap();
+
+    assert_success(&setup.push_send(&["t"], "body").await);
+
+    let received = setup.mock.received();
+    assert!(received
+        .iter()
+        .any(|r| r.name == "phone" && r.content == b"old"));
+    assert_eq!(received.len(), 3);
+    let statuses = conn
+        .interact(|c| {
+            let mut stmt = c.prepare(
+                "SELECT status, attempts FROM delivery WHERE message_id = 100 ORDER BY id",
+            )?;
+            let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+            rows.collect::<Result<Vec<(String, u32)>, _>>()
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    let delivered = (String::from("delivered"), 3);
+    assert_eq!(statuses, [delivered, (String::from("failed"), 3)]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unreadable_subscriptions_do_not_block_the_others() {
+    let setup = Setup::new("unreadable").await;
+    setup.subscribe("broken", &[]).await;
+    setup.subscribe("phone", &[]).await;
+    let conn = setup.pool.get().await.unwrap();
+    conn.interact(|c| {
+        c.execute(
+            "UPDATE subscription SET p256dh = x'0400' WHERE name = 'broken'",
+            [],
+        )
+    })
+    .await
+    .unwrap()
+    .unwrap();
+
+    let output = setup.push_send(&["t"], "body").await;
+    assert!(!output.status.success());
+
+    let received = setup.mock.received();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].name, "phone");
+    let statuses = conn
+        .interact(|c| {
+            let mut stmt = c.prepare(
+                "SELECT status, error IS NOT NULL FROM delivery
+                JOIN subscription s ON s.id = subscription_id ORDER BY name",
+            )?;
+            let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+            rows.collect::<Result<Vec<(String, bool)>, _>>()
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    let failed = (String::from("failed"), true);
+    assert_eq!(statuses, [failed, (String::from("delivered"), false)]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn deliveries_are_deleted_with_the_subscription() {
+    let setup = Setup::new("cascade").await;
+    setup.subscribe("phone", &[]).await;
+    setup.subscribe("laptop", &[]).await;
+    assert_success(&setup.push_send(&["t"], "body").await);
+
+    // by the ON DELETE CASCADE of the foreign keys
+    let conn = setup.pool.get().await.unwrap();
+    let deliveries = conn
+        .interact(|c| {
+            c.execute("DELETE FROM subscription WHERE name = 'phone'", [])?;
+            c.query_row("SELECT COUNT(*) FROM delivery", [], |r| r.get::<_, u32>(0))
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(deliveries, 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn newer_schema_is_refused() {
+    let setup = Setup::new("newer-schema").await;
+    setup.subscribe("phone", &[]).await;
+    let conn = setup.pool.get().await.unwrap();
+    conn.interact(|c| c.execute("INSERT INTO __migrations VALUES ('999_future.sql', 0)", []))
+        .await
+        .unwrap()
+        .unwrap();
+
+    let output = setup.push_send(&["t"], "body").await;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("999_future.sql"));
+    assert!(setup.mock.received().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unauthorized_pushes_are_rejected() {
+    let setup = Setup::new("unauthorized").await;
+    let client = reqwest::Client::new();
+    let push = |headers: &[(&str, &str)]| {
+        let mut req = client.post(setup.mock.endpoint("phone")).body("content");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.send()
+    };
+
+    let resp = push(&[("TTL", "10")]).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = push(&[("TTL", "10"), ("Authorization", "vapid t=a.b.c, k=d")]).await;
+    assert_eq!(resp.unwrap().status(), StatusCode::FORBIDDEN);
+    assert!(setup.mock.received().is_empty());
+}
@@ -0,0 +1,205 @@
+//! A mock push service (rfc8030) for the integration tests. It accepts push messages for
+//! the subscriptions of a single test user agent, checks the VAPID authorization and
+//! decrypts the content like a browser would. The responses can be scripted per subscription.
+
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_ENCODING, LOCATION, RETRY_AFTER};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::Router;
+use pusher::base64::{base64url_decode, base64url_encode};
+use pusher::es256::{Es256, Es256Pub};
+use pusher::jwt::verify_vapid;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+
+/// Maximum size of the push message body (rfc8030 section 7.2)
+const MAX_BODY_SIZE: usize = 4096;
+
+/// A push message that passed the checks of the mock
+#[derive(Clone, Debug)]
+pub struct Received {
+    /// The subscription that the message was sent to
+    pub name: String,
+    /// The status that the mock responded with
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    /// The decrypted content
+    pub content: Vec<u8>,
+}
+
+impl Received {
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.content).unwrap()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|v| v.to_str().unwrap())
+    }
+}
+
+struct Inner {
+    origin: String,
+    /// The key and the authentication secret of the user agent
+    key: Es256,
+    auth: [u8; 16],
+    /// Statuses to respond with by subscription, 201 when not scripted
+    responses: Mutex<HashMap<String, VecDeque<StatusCode>>>,
+    received: Mutex<Vec<Received>>,
+}
+
+#[derive(Clone)]
+pub struct MockPushService(Arc<Inner>);
+
+impl MockPushService {
+    /// Start the mock on a free local port
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin = format!("http://{}", listener.local_addr().unwrap());
+        let mock = Self(Arc::new(Inner {
+            origin,
+            key: Es256::gen().unwrap(),
+            auth: *b"mock push secret",
+            responses: Mutex::default(),
+            received: Mutex::default(),
+        }));
+        let app = Router::new()
+            .route("/push/{name}", post(push))
+            .with_state(mock.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        mock
+    }
+
+    pub fn endpoint(&self, name: &str) -> String {
+        format!("{}/push/{name}", self.0.origin)
+    }
+
+    /// The subscription as the web app would send it to the push-server
+    pub fn subscription(&self, name: &str, content_encodings: &[&str]) -> Value {
+        json!({
+            "endpoint": self.endpoint(name),
+            "name": name,
+            "expirationTime": null,
+            "keys": {
+                "auth": base64url_encode(self.0.auth),
+                "p256dh": base64url_encode(self.0.key.public_key().unwrap()),
+            },
+            "contentEncodings": content_encodings,
+        })
+    }
+
+    /// Respond to the next pushes to the subscription with `statuses`
+    pub fn respond_with(&self, name: &str, statuses: &[StatusCode]) {
+        let mut responses = self.0.responses.lock().unwrap();
+        responses
+            .entry(name.to_string())
+            .or_default()
+            .extend(statuses);
+    }
+
+    /// The pushes received so far, in the order of arrival
+    pub fn received(&self) -> Vec<Received> {
+        self.0.received.lock().unwrap().clone()
+    }
+
+    /// Check the request and decrypt the content
+    fn accept(&self, headers: &HeaderMap, body: &[u8]) -> Result<Vec<u8>, (StatusCode, String)> {
+        if body.len() > MAX_BODY_SIZE {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                String::from("body too large"),
+            ));
+        }
+        header(headers, "TTL")?
+            .parse::<u32>()
+            .map_err(|e| bad_request(format!("invalid TTL: {e}")))?;
+        let auth = header(headers, AUTHORIZATION.as_str())
+            .map_err(|(_, e)| (StatusCode::UNAUTHORIZED, e))?;
+        verify_vapid(auth, &self.0.origin).map_err(|e| (StatusCode::FORBIDDEN, e.to_string()))?;
+        let k = params(auth, ',').get("k").copied();
+        let crypto_key = params(header(headers, "Crypto-Key")?, ';');
+        if crypto_key
+            .get("p256ecdsa")
+            .is_some_and(|key| Some(*key) != k)
+        {
+            return Err(bad_request("Crypto-Key does not match the VAPID key"));
+        }
+        let (key, auth) = (&self.0.key, &self.0.auth);
+        let content = match header(headers, CONTENT_ENCODING.as_str())? {
+            "aes128gcm" => key.decrypt_content(auth, body),
+            "aesgcm" => {
+                let dh = crypto_key.get("dh").ok_or(bad_request("dh missing"))?;
+                let dh = decode(dh).and_then(|k| Es256Pub::try_from(k.as_slice()).ok());
+                let salt = params(header(headers, "Encryption")?, ';');
+                let salt = salt.get("salt").copied().and_then(decode);
+                match (dh, salt.and_then(|s| <[u8; 16]>::try_from(s).ok())) {
+                    (Some(dh), Some(salt)) => key.decrypt_content_aesgcm(&dh, auth, &salt, body),
+                    _ => return Err(bad_request("invalid dh or salt")),
+                }
+            }
+            encoding => return Err(bad_request(format!("unknown encoding {encoding}"))),
+        };
+        content.map_err(|e| bad_request(format!("decryption failed: {e}")))
+    }
+}
+
+async fn push(
+    State(mock): State<MockPushService>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let content = match mock.accept(&headers, &body) {
+        Ok(content) => content,
+        Err(resp) => return resp.into_response(),
+    };
+    let status = mock
+        .0
+        .responses
+        .lock()
+        .unwrap()
+        .get_mut(&name)
+        .and_then(VecDeque::pop_front)
+        .unwrap_or(StatusCode::CREATED);
+    let mut received = mock.0.received.lock().unwrap();
+    received.push(Received {
+        name,
+        status,
+        headers,
+        content,
+    });
+    match status {
+        StatusCode::CREATED => {
+            let location = format!("{}/message/{}", mock.0.origin, received.len());
+            (status, [(LOCATION, location)]).into_response()
+        }
+        StatusCode::TOO_MANY_REQUESTS => (status, [(RETRY_AFTER, "0")]).into_response(),
+        status => status.into_response(),
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str, (StatusCode, String)> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .ok_or(bad_request(format!("{name} missing")))
+}
+
+/// Parse `key=value` pairs separated by `sep`
+fn params(s: &str, sep: char) -> HashMap<&str, &str> {
+    s.split(sep)
+        .filter_map(|p| p.trim().split_once('='))
+        .collect()
+}
+
+fn decode(s: &str) -> Option<Vec<u8>> {
+    base64url_decode(s).ok()
+}
+
+fn bad_request(msg: impl Into<String>) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, msg.into())
+}